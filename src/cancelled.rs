@@ -0,0 +1,44 @@
+use futures::future::{AbortRegistration, Abortable, Aborted};
+use futures::task::{Context, Poll};
+use futures::Future;
+use std::fmt;
+use std::pin::Pin;
+
+/// The query, or the fork running it, was cancelled and its output won't be used.
+///
+/// Calculations of cancelled queries are dropped, nothing unwinds. Forks,
+/// [`System::query_cancellable`](crate::System::query_cancellable) and
+/// [`Runtime::query_rev`](crate::Runtime::query_rev) resolve to it, so callers can retry
+/// or give up. Queries carry it on with [`System::propagate`](crate::System::propagate).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Query cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Future returned by [`System::fork`](crate::System::fork), it resolves to [`Cancelled`]
+/// when the fork is aborted, like when its queries are cancelled.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Forked<F>(Pin<Box<Abortable<F>>>);
+
+impl<F: Future> Forked<F> {
+    pub(crate) fn new(fut: F, registration: AbortRegistration) -> Self {
+        Self(Box::pin(Abortable::new(fut, registration)))
+    }
+}
+
+impl<F: Future> Future for Forked<F> {
+    type Output = Result<F::Output, Cancelled>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0
+            .as_mut()
+            .poll(cx)
+            .map(|output| output.map_err(|Aborted| Cancelled))
+    }
+}
@@ -0,0 +1,30 @@
+use crate::QueryInfo;
+use std::fmt;
+
+/// Queries on a cycle, starting with the re-entered one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cycle {
+    queries: Vec<QueryInfo>,
+}
+
+impl Cycle {
+    pub(crate) fn new(queries: Vec<QueryInfo>) -> Self {
+        Self { queries }
+    }
+
+    pub fn queries(&self) -> &[QueryInfo] {
+        &self.queries
+    }
+}
+
+impl fmt::Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for query in &self.queries {
+            write!(f, "{} -> ", query)?;
+        }
+        match self.queries.first() {
+            Some(query) => write!(f, "{}", query),
+            None => write!(f, "?"),
+        }
+    }
+}
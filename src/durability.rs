@@ -0,0 +1,59 @@
+use crate::Revision;
+use std::sync::Mutex;
+
+/// Durability tells how often an input is expected to change.
+/// Every query remembers the lowest durability of its dependencies,
+/// so when only less durable inputs change, it's fresh without checking its dependencies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Durability {
+    #[default]
+    Low,
+    Medium,
+    High,
+}
+
+impl Durability {
+    const LEVELS: usize = 3;
+
+    fn level(self) -> usize {
+        self as usize
+    }
+}
+
+/// Last revision in which an input of given durability or higher has changed.
+#[derive(Default)]
+pub(crate) struct LastChanged(Mutex<[Revision; Durability::LEVELS]>);
+
+impl LastChanged {
+    pub fn get(&self, durability: Durability) -> Revision {
+        let revs = self.0.lock().expect("Last changed lock");
+        revs[durability.level()]
+    }
+
+    /// Copy that doesn't see later updates.
+    pub fn pinned(&self) -> Self {
+        let revs = self.0.lock().expect("Last changed lock");
+        Self(Mutex::new(*revs))
+    }
+
+    pub fn update(&self, durability: Durability, rev: Revision) {
+        let mut revs = self.0.lock().expect("Last changed lock");
+        for level in 0..=durability.level() {
+            revs[level] = rev;
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn all(&self) -> Vec<Revision> {
+        self.0.lock().expect("Last changed lock").to_vec()
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn restore(&self, revs: &[Revision]) {
+        let mut guard = self.0.lock().expect("Last changed lock");
+        for (rev, restored) in guard.iter_mut().zip(revs) {
+            *rev = *restored;
+        }
+    }
+}
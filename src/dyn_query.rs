@@ -1,8 +1,16 @@
-use crate::Runtime;
+use crate::runtime::Tracked;
+use crate::{Cancelled, Runtime};
 use async_trait::async_trait;
 use std::any::Any;
 
 #[async_trait]
 pub(crate) trait DynQuery: Send + Sync {
-    async fn calc(&self, system: &Runtime) -> Box<dyn Any + Send + Sync>;
+    /// Recalculates the query, along with what it has read this time.
+    async fn calc(
+        &self,
+        system: &Runtime,
+    ) -> Result<(Box<dyn Any + Send + Sync>, Tracked), Cancelled>;
+
+    /// Brings the query up to date, like querying it would.
+    async fn refresh(&self, system: &Runtime);
 }
@@ -0,0 +1,97 @@
+use crate::{Query, System};
+use async_trait::async_trait;
+use core::hash::{Hash, Hasher};
+use std::convert::TryFrom;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Intern is a special kind of value that you can swap for a small `Copy` id.
+/// Interned values are stored once and never change, so ids are cheap query parameters.
+pub trait Intern: 'static + Send + Sync + Hash + Eq + Clone + fmt::Debug {}
+
+pub struct InternId<T> {
+    idx: u32,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> InternId<T> {
+    pub(crate) fn new(idx: usize) -> Self {
+        let idx = u32::try_from(idx).expect("Too many interned values");
+        Self {
+            idx,
+            _value: PhantomData,
+        }
+    }
+
+    pub(crate) fn idx(self) -> usize {
+        self.idx as usize
+    }
+}
+
+impl<T> Clone for InternId<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for InternId<T> {}
+
+impl<T> PartialEq for InternId<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.idx == other.idx
+    }
+}
+
+impl<T> Eq for InternId<T> {}
+
+impl<T> Hash for InternId<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.idx.hash(state)
+    }
+}
+
+impl<T> fmt::Debug for InternId<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}", std::any::type_name::<T>(), self.idx)
+    }
+}
+
+/// `Runtime::save` fails on ids of values that aren't persisted with `Persist::with_interned`.
+#[cfg(feature = "serde")]
+impl<T: Intern> serde::Serialize for InternId<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !crate::runtime::is_interned_persisted::<T>() {
+            return Err(serde::ser::Error::custom(format!(
+                "Interned {} is not persisted",
+                std::any::type_name::<T>()
+            )));
+        }
+        self.idx.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T> serde::Deserialize<'de> for InternId<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let idx = u32::deserialize(deserializer)?;
+        Ok(Self {
+            idx,
+            _value: PhantomData,
+        })
+    }
+}
+
+/// Interned value is stored as a query key. The id is the index of its cell,
+/// so interning is tracked as a dependency like any other query.
+#[derive(Hash, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Interned<T: Intern>(pub(crate) T);
+
+#[async_trait]
+impl<T: Intern> Query for Interned<T> {
+    type Output = ();
+
+    const INTERNED: bool = true;
+
+    async fn calc<S: System>(&self, _system: &S) -> Self::Output {}
+}
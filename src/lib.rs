@@ -1,24 +1,48 @@
 mod revision;
 
+mod cancelled;
+mod cycle;
+mod durability;
 mod dyn_query;
+mod intern;
 mod invalidation;
 mod query;
 mod query_ref;
+mod query_tuple;
 mod runtime;
+mod spawner;
 mod system;
+mod try_query;
 mod reservation;
 
+pub(crate) use durability::LastChanged;
 pub(crate) use dyn_query::DynQuery;
+pub(crate) use intern::Interned;
 pub(crate) use invalidation::Invalidation;
 pub(crate) use runtime::DepIdx;
-pub(crate) use system::ForkId;
+pub(crate) use try_query::Try;
 pub(crate) use reservation::{Reservation, ReservationReader};
+pub(crate) use spawner::SpawnerSlot;
 
+pub use cancelled::{Cancelled, Forked};
+pub use cycle::Cycle;
+pub use durability::Durability;
+pub use intern::{Intern, InternId};
 pub use query::{Input, Query};
 pub use query_ref::QueryRef;
+pub use query_tuple::QueryTuple;
+pub(crate) use revision::NextRevision;
 pub use revision::Revision;
-pub use runtime::Runtime;
-pub use system::System;
+#[cfg(feature = "serde")]
+pub use runtime::Persist;
+pub use runtime::{Batch, InFlight, QueryInfo, Runtime, Scope, Snapshot, SnapshotPolicy};
+#[cfg(feature = "smol")]
+pub use spawner::SmolSpawner;
+#[cfg(feature = "tokio")]
+pub use spawner::TokioSpawner;
+pub use spawner::{JoinHandle, NoSpawner, Spawner};
+pub use system::{ForkId, System};
+pub use try_query::{QueryError, TryQuery};
 
 pub mod test_common {
     #[cfg(not(any(test, feature = "with_tests")))]
@@ -1,4 +1,4 @@
-use crate::System;
+use crate::{Cycle, System};
 use async_trait::async_trait;
 use core::hash::Hash;
 use std::fmt;
@@ -7,10 +7,33 @@ use std::fmt;
 pub trait Query: 'static + Send + Sync + Hash + PartialEq + Eq + fmt::Debug {
     type Output: Send + Sync + fmt::Debug + Eq;
 
+    /// How many memoized outputs of this query are kept, least recently used are dropped first.
+    /// `0` means unbounded.
+    const LRU_CAPACITY: usize = 0;
+
+    /// How many times a cycle head is calculated before giving up on its fixpoint.
+    const CYCLE_ITERATIONS: usize = 100;
+
+    /// Interned values, their storage is shared with snapshots so ids don't collide.
+    #[doc(hidden)]
+    const INTERNED: bool = false;
+
+    /// Inputs change whenever they're set, even without dependencies.
+    #[doc(hidden)]
+    const INPUT: bool = false;
+
     async fn calc<S: System>(&self, system: &S) -> Self::Output;
 
-    fn on_cycle(&self) -> Self::Output {
-        panic!("Cycle detected")
+    /// Output of the query calculated again by itself, through the `cycle`.
+    fn on_cycle(&self, cycle: &Cycle) -> Self::Output {
+        panic!("Cycle detected: {}", cycle)
+    }
+
+    /// Output a cycle head starts from. Queries returning `Some` don't call `on_cycle`,
+    /// the cycle is calculated again with the last output until it stops changing.
+    /// The cycle is calculated for the first time through the query it's re-entered at.
+    fn cycle_initial(&self) -> Option<Self::Output> {
+        None
     }
 }
 
@@ -30,6 +53,8 @@ where
 {
     type Output = I::Data;
 
+    const INPUT: bool = true;
+
     async fn calc<S: System>(&self, _system: &S) -> Self::Output {
         self.on_uninitialized()
     }
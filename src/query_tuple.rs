@@ -0,0 +1,33 @@
+use crate::system::query_forked;
+use crate::{Query, System};
+use futures::future::{self, BoxFuture, FutureExt};
+
+/// Tuple of queries of different types, queried together with
+/// [`System::query_join`](crate::System::query_join).
+pub trait QueryTuple: Send + 'static {
+    type Output: Send;
+
+    fn query_with<S: System>(self, system: &S) -> BoxFuture<'_, Self::Output>;
+}
+
+macro_rules! query_tuple {
+    ($join: ident; $($query: ident),+) => {
+        impl<$($query),+> QueryTuple for ($($query,)+)
+        where
+            $($query: Query, $query::Output: Clone,)+
+        {
+            type Output = ($($query::Output,)+);
+
+            #[allow(non_snake_case)]
+            fn query_with<S: System>(self, system: &S) -> BoxFuture<'_, Self::Output> {
+                let ($($query,)+) = self;
+                future::$join($(query_forked(system, $query)),+).boxed()
+            }
+        }
+    };
+}
+
+query_tuple!(join; A, B);
+query_tuple!(join3; A, B, C);
+query_tuple!(join4; A, B, C, D);
+query_tuple!(join5; A, B, C, D, E);
@@ -1,14 +1,14 @@
 #![allow(dead_code)]
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use futures::task::{AtomicWaker, Context, Poll};
+use futures::task::{Context, Poll, Waker};
 use futures::Future;
 use std::pin::Pin;
 
 #[derive(Debug)]
 struct Inner {
-    waker: AtomicWaker,
+    wakers: Mutex<Vec<Waker>>,
     ready: AtomicBool
 }
 
@@ -21,24 +21,34 @@ impl Future for ReservationReader {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.0.ready.load(Ordering::Relaxed) {
+        if self.0.ready.load(Ordering::Acquire) {
             return Poll::Ready(());
         }
 
-        self.0.waker.register(cx.waker());
+        // Every reader waiting on the reservation has to be woken, not just the last one.
+        let mut wakers = self.0.wakers.lock().expect("Reservation wakers poisoned");
+        if self.0.ready.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
 
-        if self.0.ready.load(Ordering::Relaxed) {
-            Poll::Ready(())
-        } else {
-            Poll::Pending
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
         }
+
+        Poll::Pending
+    }
+}
+
+impl ReservationReader {
+    pub fn is_ready(&self) -> bool {
+        self.0.ready.load(Ordering::Acquire)
     }
 }
 
 impl Reservation {
-    pub async fn new() -> (Self, ReservationReader) {
+    pub fn new() -> (Self, ReservationReader) {
         let inner = Arc::new(Inner {
-            waker: AtomicWaker::new(),
+            wakers: Mutex::new(Vec::new()),
             ready: AtomicBool::new(false),
         });
 
@@ -48,7 +58,14 @@ impl Reservation {
 
 impl Drop for Reservation {
     fn drop(&mut self) {
-        self.0.ready.store(true, Ordering::Relaxed);
-        self.0.waker.wake();
+        let wakers = {
+            let mut wakers = self.0.wakers.lock().expect("Reservation wakers poisoned");
+            self.0.ready.store(true, Ordering::Release);
+            std::mem::take(&mut *wakers)
+        };
+
+        for waker in wakers {
+            waker.wake();
+        }
     }
 }
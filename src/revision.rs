@@ -2,7 +2,8 @@ use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Revision(usize);
 
 impl Revision {
@@ -15,6 +16,37 @@ impl Revision {
         let id = counter.load(Ordering::SeqCst);
         Self(id)
     }
+
+    /// Counter stuck at the current revision.
+    pub(crate) fn pin(counter: &Arc<AtomicUsize>) -> Arc<AtomicUsize> {
+        Arc::new(AtomicUsize::new(counter.load(Ordering::SeqCst)))
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn restore(self, counter: &Arc<AtomicUsize>) {
+        counter.store(self.0, Ordering::SeqCst);
+    }
+}
+
+/// Revision created by the first write that changes anything.
+pub(crate) struct NextRevision<'a> {
+    counter: &'a Arc<AtomicUsize>,
+    rev: Option<Revision>,
+}
+
+impl<'a> NextRevision<'a> {
+    pub fn new(counter: &'a Arc<AtomicUsize>) -> Self {
+        Self { counter, rev: None }
+    }
+
+    pub fn get(&mut self) -> Revision {
+        let counter = self.counter;
+        *self.rev.get_or_insert_with(|| Revision::new(counter))
+    }
+
+    pub fn created(&self) -> Option<Revision> {
+        self.rev
+    }
 }
 
 impl fmt::Debug for Revision {
@@ -1,43 +1,76 @@
+mod batch;
 mod dep;
+mod in_flight;
+mod lru;
+#[cfg(feature = "serde")]
+mod persist;
 mod query_tracker;
+mod scope;
+mod snapshot;
 mod storage;
+mod storage_map;
+mod wait_graph;
 
+pub use self::batch::Batch;
 pub(crate) use self::dep::{Dep, DepIdx, DepsExt};
-use self::storage::{QueryCell, QueryStorage, Storage, CycleDetection};
+pub use self::in_flight::{InFlight, QueryInfo};
+pub(crate) use self::in_flight::{ForkHandle, ForkInfo, Frames, QueryStack};
+pub(crate) use self::query_tracker::Tracked;
+#[cfg(feature = "serde")]
+pub(crate) use self::persist::is_interned_persisted;
+#[cfg(feature = "serde")]
+pub use self::persist::Persist;
+pub use self::scope::Scope;
+use self::scope::ScopeState;
+pub use self::snapshot::{Snapshot, SnapshotPolicy};
+use self::snapshot::{SnapshotGuard, Snapshots};
+use self::storage::{CycleDetection, CycleHeads, DepCell, QueryCell, Stamps, Storage};
+use self::storage_map::StorageMap;
+use self::wait_graph::WaitGraph;
 use crate::runtime::query_tracker::QueryTracker;
-use crate::{ForkId, Input, Invalidation, Query, QueryRef, Revision, System, Reservation};
+use crate::{
+    Cancelled, Durability, ForkId, Forked, Input, Intern, InternId, Interned, Invalidation,
+    JoinHandle, LastChanged, NextRevision, NoSpawner, Query, QueryRef, Reservation, Revision,
+    Spawner, SpawnerSlot, System,
+};
 use async_trait::async_trait;
-use core::any::TypeId;
-use futures::future::{abortable, AbortHandle, Abortable, BoxFuture};
+use futures::future::{AbortHandle, BoxFuture};
 use futures::{Future, FutureExt};
-use std::collections::HashMap;
+use std::any::TypeId;
+use std::collections::HashSet;
 use std::fmt;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::RwLock;
 use tracing_futures::Instrument;
 
-type QueriesMap = HashMap<TypeId, Box<dyn Storage>>;
-
 #[derive(Default)]
 pub struct Runtime {
-    queries: Arc<RwLock<QueriesMap>>,
-    handles: Arc<RwLock<Vec<AbortHandle>>>,
+    queries: Arc<StorageMap>,
+    handles: Arc<RwLock<Vec<ForkHandle>>>,
     rev_counter: Arc<AtomicUsize>,
+    last_changed: Arc<LastChanged>,
     fork_counter: Arc<AtomicUsize>,
-    fork_id: ForkId,
+    fork: Arc<ForkInfo>,
+    snapshots: Arc<Snapshots>,
+    snapshot: Option<Arc<SnapshotGuard>>,
+    scope: Option<Arc<ScopeState>>,
+    stack: QueryStack,
+    waits: Arc<WaitGraph>,
+    frames: Arc<Frames>,
+    spawner: Arc<SpawnerSlot>,
 }
 
 impl fmt::Debug for Runtime {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "({:?}, {:?})", &self.current_rev(), self.fork_id)
+        write!(f, "({:?}, {:?})", &self.current_rev(), self.fork.id)
     }
 }
 
 #[async_trait]
 impl System for Runtime {
     async fn query_ref<Q: Query>(&self, query: Q) -> QueryRef<Q::Output> {
-        let output = self.query_inner(query).await.output();
+        let output = self.query_again(query).await.output();
         QueryRef(output.unwrap())
     }
 
@@ -46,22 +79,63 @@ impl System for Runtime {
         Q: Query,
         Q::Output: Clone,
     {
-        let output = self.query_inner(query).await.output();
+        let output = self.query_again(query).await.output();
         (*output.unwrap()).clone()
     }
 
+    async fn query_cancellable<Q>(&self, query: Q) -> Result<Q::Output, Cancelled>
+    where
+        Q: Query,
+        Q::Output: Clone,
+    {
+        let output = self.query_inner(query).await?.output();
+        Ok((*output.unwrap()).clone())
+    }
+
+    async fn intern<T: Intern>(&self, value: T) -> InternId<T> {
+        let cell = self.query_again(Interned(value)).await;
+        InternId::new(cell.idx())
+    }
+
+    async fn lookup<T: Intern>(&self, id: InternId<T>) -> T {
+        let (value, _cell) = self.lookup_inner(id).await;
+        value
+    }
+
     #[tracing::instrument(skip(f))]
-    async fn fork<F, Fut>(&self, f: F) -> Abortable<Fut>
+    async fn fork<F, Fut>(&self, f: F) -> Forked<Fut>
     where
         F: Send + Fn(Self) -> Fut,
         Fut: Future + Send,
     {
         let fork = self.fork_inner();
-        let fut = f(fork);
-        let (fut, handle) = abortable(fut);
-        self.handles.write().await.push(handle);
+        let (handle, registration) = AbortHandle::new_pair();
+        self.register_fork(&fork, handle).await;
 
-        fut
+        Forked::new(f(fork), registration)
+    }
+
+    async fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T::Output>, NoSpawner>
+    where
+        F: Send + Fn(Self) -> T,
+        T: Future + Send + 'static,
+        T::Output: Send + 'static,
+    {
+        let forked = self.fork(f).await;
+        self.spawner.spawn(forked)
+    }
+
+    async fn propagate<T: Send>(&self, cancelled: Cancelled) -> T {
+        panic!(
+            "{} outside of queries, handle it with System::query_cancellable",
+            cancelled
+        )
+    }
+
+    fn is_cancelled(&self) -> bool {
+        let snapshot_cancelled = self.snapshot.as_ref().is_some_and(|guard| guard.is_cancelled());
+        let scope_cancelled = self.scope.as_ref().is_some_and(|scope| scope.is_cancelled());
+        snapshot_cancelled || scope_cancelled || self.stack.is_cancelled()
     }
 }
 
@@ -70,288 +144,704 @@ impl Runtime {
         Revision::current(&self.rev_counter)
     }
 
-    pub async fn query_rev<Q>(&self, query: Q) -> (Q::Output, Revision)
+    /// Queries along with the revision the output last changed at,
+    /// or [`Cancelled`] if a write cancelled the query.
+    pub async fn query_rev<Q>(&self, query: Q) -> Result<(Q::Output, Revision), Cancelled>
     where
         Q: Query,
         Q::Output: Clone,
     {
-        let cell = self.query_inner(query).await;
+        let cell = self.query_inner(query).await?;
         let rev = cell.rev();
-        ((*cell.output().unwrap()).clone(), rev)
+        Ok(((*cell.output().unwrap()).clone(), rev))
+    }
+
+    /// Executor of [`System::spawn`], by default the one of the enabled `tokio` or `smol` feature.
+    pub fn set_spawner(&self, spawner: impl Spawner) {
+        self.spawner.set(spawner);
     }
 
+    /// Sets the input, returns `false` if it already had an equal value.
+    /// Nothing is canceled and no revision is created in that case,
+    /// otherwise queries that have read the input so far are canceled.
+    pub async fn set_input<Q>(&self, query: Q, data: <Q as Query>::Output) -> bool
+    where
+        Q: Input + Query,
+        Q::Output: Eq,
+    {
+        self.set_input_with_durability(query, data, Durability::Low)
+            .await
+    }
+
+    #[tracing::instrument]
+    pub async fn set_input_with_durability<Q>(
+        &self,
+        query: Q,
+        data: <Q as Query>::Output,
+        durability: Durability,
+    ) -> bool
+    where
+        Q: Input + Query,
+        Q::Output: Eq,
+    {
+        if !self.input_changed(&query, &data, durability) {
+            return false;
+        }
+
+        let changed = {
+            let _writing = self.start_write().await;
+            let mut rev = NextRevision::new(&self.rev_counter);
+            self.write_input(query, data, durability, &mut rev)
+        };
+        match changed {
+            Some(changed) => {
+                self.cancel_affected(&std::iter::once(changed).collect()).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the input, so dependents see it as uninitialized.
+    /// Cells calculated only out of the removed input are released.
     #[tracing::instrument]
-    pub async fn set_input<Q: Input + Query>(&self, query: Q, data: <Q as Query>::Output) {
-        let type_id = TypeId::of::<Q>();
+    pub async fn remove_input<Q: Input + Query>(&self, query: Q) {
+        let storage = match self.queries.typed::<Q>() {
+            Some(storage) => storage,
+            None => return,
+        };
+        if storage.read().durability(&query).is_none() {
+            return;
+        }
 
-        {
-            let mut handles = self.handles.write().await;
-            if !handles.is_empty() {
-                tracing::debug!("Canceling ongoing requests");
+        let removed: HashSet<_> = {
+            let _writing = self.start_write().await;
+            let mut rev = NextRevision::new(&self.rev_counter);
+            let removed = self.write_removal(&query, &mut rev).into_iter().collect();
+            if let Some(rev) = rev.created() {
+                self.release_removed(&removed, rev);
             }
-            for (idx, handle) in handles.drain(..).enumerate() {
-                tracing::debug!("{}", idx);
-                handle.abort();
+            removed
+        };
+        self.cancel_affected(&removed).await;
+    }
+}
+
+impl Runtime {
+    fn input_changed<Q>(&self, query: &Q, output: &Q::Output, durability: Durability) -> bool
+    where
+        Q: Input + Query,
+        Q::Output: Eq,
+    {
+        match self.queries.typed::<Q>() {
+            Some(storage) => storage.read().input_changed(query, output, durability),
+            None => true,
+        }
+    }
+
+    /// Sets the input unless it already has an equal value, returns the changed one.
+    fn write_input<Q>(
+        &self,
+        query: Q,
+        output: <Q as Query>::Output,
+        durability: Durability,
+        rev: &mut NextRevision<'_>,
+    ) -> Option<(TypeId, usize)>
+    where
+        Q: Input + Query,
+        Q::Output: Eq,
+    {
+        let storage = self.queries.typed_or_default::<Q>();
+        let mut storage = storage.write();
+        if !storage.input_changed(&query, &output, durability) {
+            return None;
+        }
+        let rev = rev.get();
+
+        // Queries that read the old value have to notice the change as well.
+        let changed = storage
+            .durability(&query)
+            .map_or(durability, |old| old.max(durability));
+
+        self.last_changed.update(changed, rev);
+        let stamps = Stamps::new(rev);
+        let query = Arc::new(query);
+        let cell =
+            storage.insert_calculated(query, output, stamps, durability, Default::default(), None);
+        Some((TypeId::of::<Q>(), cell.idx()))
+    }
+
+    fn write_removal<Q: Input + Query>(
+        &self,
+        query: &Q,
+        rev: &mut NextRevision<'_>,
+    ) -> Option<(TypeId, usize)> {
+        // Storages could be copied for snapshots since the caller looked them up.
+        let storage = self.queries.typed::<Q>()?;
+        let mut storage = storage.write();
+        let durability = storage.durability(query)?;
+        let rev = rev.get();
+
+        self.last_changed.update(durability, rev);
+        let removed = storage.remove(query, rev).expect("Removed input");
+        Some((removed.query_type, removed.query_idx))
+    }
+
+    fn release_removed(&self, removed: &HashSet<(TypeId, usize)>, rev: Revision) {
+        if removed.is_empty() {
+            return;
+        }
+        let mut removed = removed.clone();
+
+        let storages = self.queries.all();
+        loop {
+            let released = self.release_orphans(&storages, &removed, rev);
+            if released.is_empty() {
+                break;
             }
+            removed.extend(released);
         }
+    }
+
+    /// Releases cells that were calculated out of removed queries and values that never change
+    /// (like interned ones), nothing can reach them any more.
+    fn release_orphans(
+        &self,
+        storages: &[(TypeId, Arc<dyn Storage>)],
+        removed: &HashSet<(TypeId, usize)>,
+        rev: Revision,
+    ) -> Vec<(TypeId, usize)> {
+        let is_removed = |dep: &Dep| removed.contains(&(dep.query_type(), dep.idx.query_idx));
+        let is_constant = |dep: &Dep| {
+            let storage = self.queries.get(dep.query_type()).expect("Dep storage");
+            storage.is_constant(dep)
+        };
 
-        if !self.read_queries().await.contains_key(&type_id) {
-            self.write_queries()
-                .await
-                .insert(type_id, Box::new(QueryStorage::<Q>::default()));
+        let mut released = vec![];
+        for (_, storage) in storages {
+            for (idx, deps) in storage.dependents(removed) {
+                if deps.iter().all(|dep| is_removed(dep) || is_constant(dep)) {
+                    let dep = storage.release(idx, rev);
+                    released.push((dep.query_type, dep.query_idx));
+                }
+            }
         }
 
-        let output = data;
+        released
+    }
 
-        let mut guard = self.write_queries().await;
-        let storage = guard.get_mut(&type_id).expect("Query storage");
-        let storage = storage
-            .as_any_mut()
-            .downcast_mut::<QueryStorage<Q>>()
-            .expect("Couldn't downcast to storage");
+    /// Deals with snapshots, writes are done under the returned guard.
+    /// Affected queries are canceled once it's released.
+    async fn start_write(&self) -> std::sync::MutexGuard<'_, ()> {
+        loop {
+            self.snapshots.before_write().await;
+
+            // Snapshots taken while waiting are dealt with again.
+            let writing = self.snapshots.writing();
+            if self.snapshots.is_ready_for_write() {
+                self.queries.unshare();
+                return writing;
+            }
+        }
+    }
 
-        let rev = Revision::new(&self.rev_counter);
-        storage.insert_calculated(Arc::new(query), output, rev, Default::default());
+    /// Keeps the handle of the fork, so it can be aborted along with its scope or
+    /// when it's affected by a write.
+    async fn register_fork(&self, fork: &Runtime, handle: AbortHandle) {
+        if let Some(scope) = &self.scope {
+            scope.push(ForkHandle::new(fork, handle.clone()));
+        }
+        let mut handles = self.handles.write().await;
+        handles.retain(|handle| !handle.is_completed());
+        handles.push(ForkHandle::new(fork, handle));
     }
-}
 
-impl Runtime {
     pub(crate) fn fork_no_inc(&self) -> Self {
         Self {
             queries: self.queries.clone(),
             handles: self.handles.clone(),
             rev_counter: self.rev_counter.clone(),
+            last_changed: self.last_changed.clone(),
             fork_counter: self.fork_counter.clone(),
-            fork_id: self.fork_id,
+            fork: self.fork.clone(),
+            snapshots: self.snapshots.clone(),
+            snapshot: self.snapshot.clone(),
+            scope: self.scope.clone(),
+            stack: self.stack.clone(),
+            waits: self.waits.clone(),
+            frames: self.frames.clone(),
+            spawner: self.spawner.clone(),
         }
     }
 
     fn fork_inner(&self) -> Self {
-        let fork_id = ForkId::new(&self.fork_counter);
+        let fork = Arc::new(ForkInfo {
+            id: ForkId::new(&self.fork_counter),
+            started_at: self.current_rev(),
+        });
         Self {
             queries: self.queries.clone(),
             handles: self.handles.clone(),
             rev_counter: self.rev_counter.clone(),
+            last_changed: self.last_changed.clone(),
             fork_counter: self.fork_counter.clone(),
-            fork_id,
+            fork,
+            snapshots: self.snapshots.clone(),
+            snapshot: self.snapshot.clone(),
+            scope: self.scope.clone(),
+            stack: self.stack.clone(),
+            waits: self.waits.clone(),
+            frames: self.frames.clone(),
+            spawner: self.spawner.clone(),
         }
     }
 
     #[tracing::instrument]
-    async fn write_queries(&self) -> RwLockWriteGuard<'_, QueriesMap> {
-        tracing::trace!("WRITE RW LOCK");
-        self.queries.write().await
-    }
+    async fn lookup_inner<T: Intern>(&self, id: InternId<T>) -> (T, QueryCell<Interned<T>>) {
+        let storage = self.queries.typed::<Interned<T>>().expect("Intern storage");
+        let storage = storage.read();
 
-    #[tracing::instrument]
-    async fn read_queries(&self) -> RwLockReadGuard<'_, QueriesMap> {
-        tracing::trace!("READ RW LOCK");
-        self.queries.read().await
+        let (key, cell) = storage.get_by_idx(id.idx());
+        (key.0.clone(), cell.clone())
     }
 
     #[tracing::instrument]
-    async fn dep_rev(&self, dep: &Dep) -> Revision {
-        let guard = self.read_queries().await;
-        let storage = guard.get(&dep.query_type()).expect("Dep storage");
+    fn dep_rev(&self, dep: &Dep) -> Revision {
+        let storage = self.queries.get(dep.query_type()).expect("Dep storage");
         storage.dep_rev(dep)
     }
 
-    async fn reserve_query<Q: Query>(&self, query: Arc<Q>, type_id: TypeId, current_rev: Revision) -> Reservation {
-        #![allow(dead_code)]
+    /// Dependencies with the revisions they have now.
+    fn refresh_deps(&self, deps: &[Dep]) -> Vec<Dep> {
+        deps.iter()
+            .map(|dep| Dep {
+                query_rev: self.dep_rev(dep),
+                ..*dep
+            })
+            .collect()
+    }
 
-        let mut global_guard = self.write_queries().await;
-        let storage = global_guard.get_mut(&type_id).expect("Query storage");
-        let storage = storage
-            .as_any_mut()
-            .downcast_mut::<QueryStorage<Q>>()
-            .expect("Couldn't downcast to storage");
+    fn reserve_query<Q: Query>(&self, query: Arc<Q>, current_rev: Revision) -> Reservation {
+        let storage = self.queries.typed::<Q>().expect("Query storage");
+        let mut storage = storage.write();
 
-        storage.reserve(query.clone(), self.fork_id, current_rev).await
+        storage.reserve(query, self.fork.id, current_rev)
     }
 
-    #[tracing::instrument(skip(type_id, reservation))]
-    async fn recalc_query<Q: Query>(&self, query: Arc<Q>, type_id: TypeId, current_rev: Revision, reservation: Reservation) -> QueryCell<Q> {
+    /// `rev` is used when the query has no dependencies.
+    /// `changed_at` is kept only when the output is known to be the same, like after eviction.
+    ///
+    /// Cycle head with an initial output is calculated until the output stops changing.
+    #[tracing::instrument(skip(reservation))]
+    async fn recalc_query<Q: Query>(
+        &self,
+        query: Arc<Q>,
+        rev: Revision,
+        changed_at: Revision,
+        reservation: Reservation,
+    ) -> Result<QueryCell<Q>, Cancelled> {
         let _local_lock = reservation;
 
-        let tracker = QueryTracker::new(self);
+        let verified_at = self.current_rev();
+        let storage = self.queries.typed::<Q>().expect("Query storage");
+        let idx = storage.read().idx(&query).expect("Reserved query");
 
-        let output = query.calc(&tracker).await;
+        let mut provisional = query.cycle_initial().map(Arc::new);
+        let mut iterations = 0;
+        let (output, tracked, on_cycle) = loop {
+            if let Some(output) = &provisional {
+                storage.write().start_iteration(idx, output.clone());
+            }
+            let tracker = QueryTracker::new(self, idx, &query);
+            let output = match tracker.calc(&*query).await {
+                Ok(output) => output,
+                Err(cancelled) => {
+                    if provisional.is_some() {
+                        storage.write().end_iteration(idx);
+                    }
+                    tracing::debug!("Query {:?} cancelled", query);
+                    return Err(cancelled);
+                }
+            };
+            let tracked = tracker.into_tracked().await;
+
+            let read = provisional.is_some() && storage.write().end_iteration(idx);
+            match provisional {
+                Some(last) if read && *last != output => {
+                    iterations += 1;
+                    if iterations >= Q::CYCLE_ITERATIONS {
+                        panic!("Cycle of {:?} didn't converge in {} iterations", query, iterations);
+                    }
+                    tracing::debug!("Cycle of {:?} changed, iteration {}", query, iterations);
+                    provisional = Some(Arc::new(output));
+                }
+                _ => break (output, tracked, read),
+            }
+        };
+
+        let Tracked { deps, mut pending, .. } = tracked;
+        let cycle = if on_cycle {
+            let head = DepIdx::of::<Q>(idx);
+            pending.retain(|pending| *pending != head);
+            self.finalize_cycle(&deps, head);
+
+            let mut heads = pending.clone();
+            heads.push(head);
+            Some(Arc::new(CycleHeads { heads, pending }))
+        } else {
+            CycleHeads::provisional(pending)
+        };
 
-        let deps = tracker.into_deps();
         let deps_rev = deps.last_rev();
-        let rev = deps_rev.unwrap_or(current_rev);
+        let stamps = Stamps {
+            rev: deps_rev.unwrap_or(rev),
+            changed_at,
+            verified_at,
+        };
+        let durability = deps.durability();
 
-        {
-            let mut guard = self.write_queries().await;
-            let storage = guard.get_mut(&type_id).expect("Query storage");
-            let storage = storage
-                .as_any_mut()
-                .downcast_mut::<QueryStorage<Q>>()
-                .expect("Couldn't downcast to storage");
+        let mut storage = storage.write();
+        Ok(storage.insert_calculated(query, output, stamps, durability, deps, cycle))
+    }
 
-            storage.insert_calculated(query, output, rev, deps)
+    /// Outputs calculated in the last iteration of the cycle of `head` are final now,
+    /// unless they wait for other heads too.
+    fn finalize_cycle(&self, deps: &[Dep], head: DepIdx) {
+        let mut deps = deps.to_vec();
+        while let Some(dep) = deps.pop() {
+            let storage = self.queries.get(dep.query_type()).expect("Dep storage");
+            if let Some(dep_deps) = storage.finalize(&dep, &head) {
+                deps.extend(dep_deps.iter());
+            }
         }
     }
 
     async fn recalc_outdated_dep(
         &self,
         dep: &Dep,
-        caused_by: DepIdx,
+        storage: &dyn Storage,
+        durability: Durability,
         current_rev: Revision,
-    ) -> Invalidation {
-        let type_id = dep.query_type();
-        let query = {
-            let guard = self.read_queries().await;
-            let storage = guard.get(&type_id).expect("Query dep storage");
-            storage.dyn_query(dep)
-        };
-        let output = query.calc(self).await;
-
-        {
-            let mut guard = self.write_queries().await;
-            let storage = guard.get_mut(&type_id).expect("Query dep storage");
-            storage.update_output_dyn(dep, caused_by, output, current_rev)
+    ) -> Option<DepCell> {
+        let query = storage.dyn_query(&dep.idx)?;
+        match query.calc(self).await {
+            Ok((output, tracked)) => {
+                Some(storage.update_output_dyn(dep, output, tracked, current_rev))
+            }
+            // The queries waiting for it are cancelled along, it counts as changed meanwhile.
+            Err(Cancelled) => Some(DepCell::changed_now(durability, current_rev)),
         }
     }
 
-    async fn recalc_revisioned_dep(&self, dep: &Dep, caused_by: DepIdx, rev: Revision) {
-        let type_id = dep.query_type();
-
-        let mut guard = self.write_queries().await;
-        let storage = guard.get_mut(&type_id).expect("Query dep storage");
-        storage.update_dep_rev(dep, caused_by, rev)
-    }
-
-    async fn recalc_rev(
+    fn recalc_rev<Q: Query>(
         &self,
-        type_id: TypeId,
         query_idx: usize,
-        caused_by: DepIdx,
+        deps: &[Dep],
         rev: Revision,
+        current_rev: Revision,
     ) {
-        let mut guard = self.write_queries().await;
-        let storage = guard.get_mut(&type_id).expect("Query rev storage");
-        storage.update_rev(query_idx, caused_by, rev)
+        let deps = self.refresh_deps(deps);
+        let storage = self.queries.typed::<Q>().expect("Query rev storage");
+        let mut storage = storage.write();
+        storage.update_rev(query_idx, rev, deps);
+        storage.verify(query_idx, current_rev);
+    }
+
+    fn verify<Q: Query>(&self, query_idx: usize, current_rev: Revision) {
+        let storage = self.queries.typed::<Q>().expect("Query verify storage");
+        storage.write().verify(query_idx, current_rev);
     }
 
-    async fn invalidation(&self, deps: &[Dep]) -> Invalidation {
+    /// Compares dependencies with what a cell verified at `verified_at` has read.
+    async fn invalidation(&self, deps: &[Dep], verified_at: Revision) -> Invalidation {
         let mut invalidation = Invalidation::Fresh;
 
         for dep in deps {
-            let dep_invalidation = self.check_invalidate(dep).await;
-            invalidation += dep_invalidation;
+            let dep_cell = self.verify_dep(dep).await;
+            let dep_invalidate = dep_cell.invalidation(dep, verified_at);
+            tracing::debug!({ ?dep_invalidate }, "Dep {:?}", dep);
+            invalidation += dep_invalidate;
+
+            // Other dependencies may not be even read by the recalculated query.
+            if let Invalidation::Outdated(..) = invalidation {
+                break;
+            }
         }
 
         invalidation
     }
 
-    fn check_invalidate<'a>(&'a self, current_dep: &'a Dep) -> BoxFuture<'a, Invalidation> {
+    /// Nothing as durable as the query has changed since its revision, so it has to be fresh.
+    fn is_durable(&self, rev: Revision, durability: Durability) -> bool {
+        self.last_changed.get(durability) <= rev
+    }
+
+    async fn cell_invalidation<Q: Query>(&self, cell: &QueryCell<Q>) -> Invalidation {
+        let verified_at = cell.stamps().verified_at;
+        if self.is_durable(verified_at, cell.durability()) {
+            return Invalidation::Fresh;
+        }
+        self.invalidation(cell.deps(), verified_at).await
+    }
+
+    /// Brings the dependency up to date with the current revision.
+    /// Every cell is verified at most once per revision, then its stamps are returned as they are.
+    fn verify_dep<'a>(&'a self, dep: &'a Dep) -> BoxFuture<'a, DepCell> {
         use Invalidation::*;
         async move {
-            let mut invalidation = Fresh;
+            let current_rev = self.current_rev();
+            let storage = self.queries.get(dep.query_type()).expect("Dep storage");
+
+            let mut dep_cell = storage.dep_cell(dep, self.fork.id, current_rev);
+            if let Some((fork, lock)) = dep_cell.lock.take() {
+                match self.waits.wait(self.fork.id, fork, dep.idx, &self.stack, &lock) {
+                    Ok(_wait) => lock.await,
+                    Err(cycle) => {
+                        tracing::debug!("Dep {:?} waits for itself: {}", dep, cycle);
+                        return DepCell::changed_now(dep_cell.durability, current_rev);
+                    }
+                }
+                dep_cell = storage.dep_cell(dep, self.fork.id, current_rev);
+            }
 
-            for dep in current_dep.deps() {
-                let dep_invalidate = self.check_invalidate(dep).await;
-                tracing::debug!({ ?dep_invalidate }, "Dep {:?}", dep);
-                invalidation += dep_invalidate;
+            let verified_at = dep_cell.stamps.verified_at;
+            let is_durable = self.is_durable(verified_at, dep_cell.durability);
+            if dep_cell.removed || (is_durable && !dep_cell.provisional) {
+                return dep_cell;
             }
 
-            match invalidation {
-                Outdated(rev, idx) => self.recalc_outdated_dep(current_dep, idx, rev).await,
-                Revisioned(rev, idx) => {
-                    self.recalc_revisioned_dep(current_dep, idx, rev).await;
-                    Revisioned(rev, idx)
+            // Cycles are brought up to date through their heads, walking them would never end.
+            if dep_cell.on_cycle {
+                if let Some(query) = storage.dyn_query(&dep.idx) {
+                    query.refresh(self).await;
                 }
-                Fresh => {
-                    let current_rev = self.dep_rev(current_dep).await;
-                    current_dep.check_outdated(current_rev)
+                return storage.dep_cell(dep, self.fork.id, current_rev);
+            }
+
+            // Walk the dependencies of the dependency, stored in its own cell.
+            match self.invalidation(&dep_cell.deps, verified_at).await {
+                Outdated(..) => match self
+                    .recalc_outdated_dep(dep, &*storage, dep_cell.durability, current_rev)
+                    .await
+                {
+                    Some(dep_cell) => dep_cell,
+                    None => {
+                        tracing::debug!("Query {:?} removed. Outdated!", dep);
+                        storage.dep_cell(dep, self.fork.id, current_rev)
+                    }
+                },
+                Revisioned(rev, _idx) => {
+                    let deps = self.refresh_deps(&dep_cell.deps);
+                    storage.update_dep_rev(dep, rev, deps, current_rev)
                 }
+                Fresh => storage.verify_dep(dep, current_rev),
             }
         }
-        .instrument(tracing::info_span!("check_invalidate", dep = ?current_dep))
+        .instrument(tracing::info_span!("verify_dep", dep = ?dep))
         .boxed()
     }
 
+    /// Cell that was already validated, with nothing changed since.
+    fn verified_cell<Q: Query>(&self, query: &Q) -> Option<QueryCell<Q>> {
+        let storage = self.queries.typed_or_default::<Q>();
+        let storage = storage.read();
+        storage.try_verified(query, |durability| self.last_changed.get(durability))
+    }
+
     #[tracing::instrument]
-    async fn query_inner<Q: Query>(&self, query: Q) -> QueryCell<Q> {
-        let type_id = TypeId::of::<Q>();
-        let query = Arc::new(query);
-        let current_rev = self.current_rev();
+    async fn query_inner<Q: Query>(&self, query: Q) -> Result<QueryCell<Q>, Cancelled> {
+        // Fast path, without allocating the key.
+        if let Some(cell) = self.verified_cell(&query) {
+            return Ok(cell);
+        }
 
-        let contains_query = {
-            let mut guard = self.write_queries().await;
-            let storage = guard.entry(type_id)
-                .or_insert_with(|| Box::new(QueryStorage::<Q>::default()));
+        // Boxed, so deep chains of queries keep only small futures on the stack.
+        self.query_cell(Arc::new(query)).boxed().await
+    }
 
-            let storage = storage.as_any_mut()
-                    .downcast_mut::<QueryStorage<Q>>()
-                    .expect("Query storage");
+    /// Queries outside of queries, calculating them again if a write cancels them.
+    /// Panics if the scope or snapshot is cancelled, or the query gave up by itself.
+    async fn query_again<Q: Query>(&self, query: Q) -> QueryCell<Q> {
+        if let Some(cell) = self.verified_cell(&query) {
+            return cell;
+        }
 
-            if storage.contains_query(&query) {
-                None
-            }
-            else {
-                let local_lock = storage.reserve(query.clone(), self.fork_id, current_rev).await;
-                Some(local_lock)
+        let query = Arc::new(query);
+        loop {
+            let started_at = self.current_rev();
+            match self.query_cell(query.clone()).await {
+                Ok(cell) => return cell,
+                // Only a write can make another attempt end differently.
+                Err(cancelled) if self.is_cancelled() || self.current_rev() == started_at => {
+                    return self.propagate(cancelled).await
+                }
+                Err(Cancelled) => tracing::debug!("Query {:?} cancelled, again", query),
             }
-        };
-
-        if let Some(local_lock) = contains_query {
-            self.recalc_query(query, type_id, current_rev, local_lock).await
-        } else {
-            let get_cell_fn = || async {
-                let guard = self.read_queries().await;
-                let storage = guard
-                    .get(&type_id)
-                    .expect("Query storage")
-                    .as_any()
-                    .downcast_ref::<QueryStorage<Q>>()
-                    .expect("Couldn't downcast to storage");
-
-                storage.get(&query).clone()
-            };
+        }
+    }
 
-            let mut cell = get_cell_fn().await;
+    /// Brings the query up to date, like querying it would.
+    /// A cancelled one is left to the query cancelled along with it.
+    pub(crate) async fn refresh_query<Q: Query>(&self, query: Arc<Q>) {
+        if self.verified_cell(&*query).is_none() {
+            let _ = self.query_cell(query).await;
+        }
+    }
 
-            match cell.detect_cycle_or_lock(self.fork_id, self.current_rev()) {
-                CycleDetection::Locked(lock) => {
-                    lock.await;
-                    cell = get_cell_fn().await;
-                },
-                CycleDetection::CycleDetected => {
-                    cell.on_cycle(&query);
-                },
-                CycleDetection::Canceled => {
-                    let local_lock = self.reserve_query(query.clone(), type_id, current_rev).await;
-                    return self.recalc_query(query, type_id, current_rev, local_lock).await;
-                },
-                _ => (),
-            }
+    async fn query_cell<Q: Query>(&self, query: Arc<Q>) -> Result<QueryCell<Q>, Cancelled> {
+        // Validation has its own frame, so it's not kept on the stack while recalculating.
+        let recalc = match self.validate_cell(&query).await {
+            Ok(cell) => return Ok(cell),
+            Err(recalc) => recalc,
+        };
 
-            tracing::debug!("Load cell: {:?}", cell);
+        let reservation = match recalc.reservation {
+            Some(reservation) => reservation,
+            None => self.reserve_query(query.clone(), recalc.current_rev),
+        };
+        self.recalc_query(query, recalc.rev, recalc.changed_at, reservation)
+            .boxed()
+            .await
+    }
 
-            if cell.deps().is_empty() {
-                return cell;
+    /// Returns the cell if it's up to date, otherwise how it should be recalculated.
+    async fn validate_cell<Q: Query>(&self, query: &Arc<Q>) -> Result<QueryCell<Q>, Recalc> {
+        let current_rev = self.current_rev();
+        let outdated = Recalc {
+            current_rev,
+            rev: current_rev,
+            changed_at: current_rev,
+            reservation: None,
+        };
+        let storage = self.queries.typed_or_default::<Q>();
+
+        let cached = storage.read().try_get(query).cloned();
+        let mut cell = match cached {
+            Some(cell) => cell,
+            None => {
+                let local_lock = {
+                    let mut storage = storage.write();
+                    match storage.try_get(query) {
+                        Some(cell) => Err(cell.clone()),
+                        None => Ok(storage.reserve(query.clone(), self.fork.id, current_rev)),
+                    }
+                };
+                match local_lock {
+                    Ok(local_lock) => {
+                        return Err(Recalc {
+                            reservation: Some(local_lock),
+                            ..outdated
+                        })
+                    }
+                    Err(cell) => cell,
+                }
             }
+        };
 
-            tracing::debug!("Should I invalidate?");
-            let invalidation = self.invalidation(cell.deps()).await;
-            tracing::debug!("Invalidation: {:?}", invalidation);
+        let idx = DepIdx::of::<Q>(cell.idx());
+        let cycle = loop {
+            match cell.detect_cycle_or_lock(self.fork.id, current_rev) {
+                // Forks waiting for each other are on a cycle as well.
+                CycleDetection::Locked(fork, lock) => {
+                    match self.waits.wait(self.fork.id, fork, idx, &self.stack, &lock) {
+                        Ok(_wait) => lock.await,
+                        Err(cycle) => break Some(cycle),
+                    }
+                }
+                CycleDetection::Abandoned => {
+                    let taken = storage.write().take_over(cell.idx(), self.fork.id, current_rev);
+                    if let Some(reservation) = taken {
+                        tracing::debug!("Query {:?} abandoned. Recalc!", query);
+                        return Err(Recalc {
+                            reservation: Some(reservation),
+                            ..outdated
+                        });
+                    }
+                }
+                CycleDetection::CycleDetected => break Some(self.stack.cycle(&idx)),
+                CycleDetection::Canceled => return Err(outdated),
+                CycleDetection::Ok => break None,
+            }
+            // Checked again, the calculation could have been abandoned.
+            cell = storage.read().get(query).clone();
+        };
+        if let Some(cycle) = cycle {
+            let output = storage.write().cycle_output(cell.idx());
+            match output {
+                Some(output) => return Ok(cell.provisional(output)),
+                None => cell.on_cycle(query, &cycle),
+            }
+        }
 
-            match invalidation {
-                Invalidation::Outdated(_rev, _idx) => {
-                    tracing::debug!("Query {:?} outdated. Recalc!", &query);
-                    let local_lock = self.reserve_query(query.clone(), type_id, current_rev).await;
-                    self.recalc_query(query, type_id, current_rev, local_lock).await
+        tracing::debug!("Load cell: {:?}", cell);
+
+        // Cycles are validated by calculating their heads again.
+        let is_durable = self.is_durable(cell.stamps().verified_at, cell.durability());
+        if cell.is_provisional() || (!cell.heads().is_empty() && !is_durable) {
+            let head = DepIdx::of::<Q>(cell.idx());
+            if !cell.is_provisional() && !cell.heads().contains(&head) {
+                for head in cell.heads() {
+                    let storage = self.queries.get(head.query_type).expect("Head storage");
+                    if let Some(query) = storage.dyn_query(head) {
+                        query.refresh(self).await;
+                    }
                 }
-                Invalidation::Revisioned(rev, idx) => {
-                    tracing::debug!("Query {:?} outdated. Update revision!", &query);
-                    let query_idx = cell.idx();
-                    self.recalc_rev(type_id, query_idx, idx, rev).await;
-                    cell
+                if let Some(cell) = self.verified_cell(&**query) {
+                    return Ok(cell);
                 }
-                Invalidation::Fresh => cell,
+            }
+
+            tracing::debug!("Query {:?} on a cycle. Recalc!", query);
+            return Err(outdated);
+        }
+
+        if cell.is_evicted() {
+            // Dependencies that didn't change let us keep the revisions, so dependents stay fresh.
+            let (rev, changed_at) = match self.cell_invalidation(&cell).await {
+                Invalidation::Outdated(..) => (current_rev, current_rev),
+                Invalidation::Revisioned(rev, _idx) => (rev, cell.stamps().changed_at),
+                Invalidation::Fresh => (cell.rev(), cell.stamps().changed_at),
+            };
+            tracing::debug!("Query {:?} evicted. Recalc!", query);
+            return Err(Recalc {
+                rev,
+                changed_at,
+                ..outdated
+            });
+        }
+
+        if cell.deps().is_empty() {
+            return Ok(cell);
+        }
+
+        tracing::debug!("Should I invalidate?");
+        let invalidation = self.cell_invalidation(&cell).await;
+        tracing::debug!("Invalidation: {:?}", invalidation);
+
+        match invalidation {
+            Invalidation::Outdated(_rev, _idx) => {
+                tracing::debug!("Query {:?} outdated. Recalc!", query);
+                Err(outdated)
+            }
+            Invalidation::Revisioned(rev, _idx) => {
+                tracing::debug!("Query {:?} outdated. Update revision!", query);
+                let query_idx = cell.idx();
+                self.recalc_rev::<Q>(query_idx, cell.deps(), rev, current_rev);
+                Ok(cell)
+            }
+            Invalidation::Fresh => {
+                self.verify::<Q>(cell.idx(), current_rev);
+                Ok(cell)
             }
         }
     }
 }
+
+/// Query that has to be calculated again, `rev` and `changed_at` are stamped on the new cell.
+struct Recalc {
+    current_rev: Revision,
+    rev: Revision,
+    changed_at: Revision,
+    /// Made already when the query had no cell.
+    reservation: Option<Reservation>,
+}
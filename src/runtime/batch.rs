@@ -0,0 +1,97 @@
+use super::Runtime;
+use crate::{Durability, Input, NextRevision, Query};
+use std::any::TypeId;
+use std::collections::HashSet;
+use std::fmt;
+
+type Write = Box<dyn FnOnce(&Runtime, &mut NextRevision<'_>, &mut Written) + Send>;
+
+/// Inputs changed by the writes so far.
+#[derive(Default)]
+struct Written {
+    changed: HashSet<(TypeId, usize)>,
+    removed: HashSet<(TypeId, usize)>,
+}
+
+/// Input changes collected by [`Runtime::batch`], applied together under one revision.
+#[derive(Default)]
+pub struct Batch {
+    writes: Vec<Write>,
+}
+
+impl fmt::Debug for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Batch")
+            .field("writes", &self.writes.len())
+            .finish()
+    }
+}
+
+impl Batch {
+    pub fn set_input<Q>(&mut self, query: Q, data: <Q as Query>::Output)
+    where
+        Q: Input + Query,
+        Q::Output: Eq,
+    {
+        self.set_input_with_durability(query, data, Durability::Low)
+    }
+
+    pub fn set_input_with_durability<Q>(
+        &mut self,
+        query: Q,
+        data: <Q as Query>::Output,
+        durability: Durability,
+    ) where
+        Q: Input + Query,
+        Q::Output: Eq,
+    {
+        self.writes.push(Box::new(move |runtime, rev, written| {
+            let changed = runtime.write_input(query, data, durability, rev);
+            written.changed.extend(changed);
+        }));
+    }
+
+    pub fn remove_input<Q: Input + Query>(&mut self, query: Q) {
+        self.writes.push(Box::new(move |runtime, rev, written| {
+            let removed = runtime.write_removal(&query, rev);
+            written.changed.extend(removed);
+            written.removed.extend(removed);
+        }));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+impl Runtime {
+    /// Applies all input changes made by `f` under a single new revision, with one cancellation.
+    /// Nothing is applied if `f` returns an error, and no revision is created if nothing changed.
+    #[tracing::instrument(skip(f))]
+    pub async fn batch<F, T, E>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Batch) -> Result<T, E>,
+    {
+        let mut batch = Batch::default();
+        let out = f(&mut batch)?;
+        if batch.is_empty() {
+            return Ok(out);
+        }
+
+        let written = {
+            let _writing = self.start_write().await;
+            let mut rev = NextRevision::new(&self.rev_counter);
+            let mut written = Written::default();
+            for write in batch.writes {
+                write(self, &mut rev, &mut written);
+            }
+            if let Some(rev) = rev.created() {
+                self.release_removed(&written.removed, rev);
+            }
+            written
+        };
+        self.cancel_affected(&written.changed).await;
+
+        Ok(out)
+    }
+}
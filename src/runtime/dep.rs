@@ -1,4 +1,4 @@
-use crate::{Invalidation, Revision};
+use crate::{Durability, Revision};
 use std::any::TypeId;
 use std::fmt;
 
@@ -9,53 +9,58 @@ pub(crate) struct DepIdx {
     pub(crate) query_idx: usize,
 }
 
+impl DepIdx {
+    pub fn of<Q: 'static>(query_idx: usize) -> Self {
+        Self {
+            query_name: std::any::type_name::<Q>(),
+            query_type: TypeId::of::<Q>(),
+            query_idx,
+        }
+    }
+}
+
 impl fmt::Debug for DepIdx {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} @ {}", self.query_name, self.query_idx)
     }
 }
 
-#[derive(Clone)]
+/// Edge to the storage slot of a dependency, as it was when the dependent read it.
+/// Dependencies of the dependency are kept only in its own cell.
+#[derive(Copy, Clone)]
 pub(crate) struct Dep {
     pub(crate) idx: DepIdx,
     pub(crate) query_rev: Revision,
-    pub(crate) query_deps: Vec<Dep>,
+    pub(crate) durability: Durability,
 }
 
 impl Dep {
     pub fn query_type(&self) -> TypeId {
         self.idx.query_type
     }
-
-    pub fn check_outdated(&self, current_rev: Revision) -> Invalidation {
-        if self.query_rev < current_rev {
-            Invalidation::Outdated(current_rev, self.idx)
-        } else {
-            Invalidation::Fresh
-        }
-    }
-
-    pub fn deps(&self) -> &[Dep] {
-        &self.query_deps
-    }
 }
 
 impl fmt::Debug for Dep {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "({:?}: {:?})", self.query_rev, self.idx,)?;
-        if !self.query_deps.is_empty() {
-            write!(f, "=>{:?}", self.query_deps)?;
-        }
-        Ok(())
+        write!(f, "({:?}: {:?})", self.query_rev, self.idx)
     }
 }
 
 pub(crate) trait DepsExt {
     fn last_rev(&self) -> Option<Revision>;
+    fn durability(&self) -> Durability;
 }
 
-impl DepsExt for Vec<Dep> {
+impl DepsExt for [Dep] {
     fn last_rev(&self) -> Option<Revision> {
         self.iter().map(|d| d.query_rev).max()
     }
+
+    /// Query without dependencies never changes, hence it's the most durable.
+    fn durability(&self) -> Durability {
+        self.iter()
+            .map(|d| d.durability)
+            .min()
+            .unwrap_or(Durability::High)
+    }
 }
@@ -0,0 +1,276 @@
+use super::{Dep, DepIdx, Runtime, Tracked};
+use crate::{Cycle, ForkId, Revision};
+use futures::future::AbortHandle;
+use std::any::TypeId;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::RwLock;
+
+/// Type and `Debug` representation of a query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryInfo {
+    pub type_name: &'static str,
+    pub key: String,
+}
+
+impl fmt::Display for QueryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.key, self.type_name)
+    }
+}
+
+/// Fork started by [`System::fork`](crate::System::fork), not completed yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InFlight {
+    pub fork: ForkId,
+    /// Revision the fork was started at.
+    pub started_at: Revision,
+    /// Innermost query the fork is calculating, if any.
+    pub query: Option<QueryInfo>,
+}
+
+/// Shared by all runtimes of a fork, the fork is in flight until the last one is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct ForkInfo {
+    pub id: ForkId,
+    pub started_at: Revision,
+}
+
+/// Queries calculated by a fork, the innermost on top.
+/// Forks continue the stack of the query they were forked from.
+#[derive(Clone, Default)]
+pub(crate) struct QueryStack(Option<Arc<Frame>>);
+
+struct Frame {
+    idx: DepIdx,
+    query: Arc<dyn fmt::Debug + Send + Sync>,
+    fork: ForkId,
+    /// What the query has read so far.
+    tracked: Weak<RwLock<Tracked>>,
+    cancelled: AtomicBool,
+    parent: QueryStack,
+}
+
+impl QueryStack {
+    /// Pushes the query calculated by `fork`, it's registered in `frames` until it's popped.
+    pub fn push(
+        &self,
+        frames: &Frames,
+        idx: DepIdx,
+        query: Arc<dyn fmt::Debug + Send + Sync>,
+        fork: ForkId,
+        tracked: &Arc<RwLock<Tracked>>,
+    ) -> Self {
+        let frame = Arc::new(Frame {
+            idx,
+            query,
+            fork,
+            tracked: Arc::downgrade(tracked),
+            cancelled: AtomicBool::new(false),
+            parent: self.clone(),
+        });
+        frames.register(&frame);
+        Self(Some(frame))
+    }
+
+    fn frames(&self) -> impl Iterator<Item = &Arc<Frame>> {
+        std::iter::successors(self.0.as_ref(), |frame| frame.parent.0.as_ref())
+    }
+
+    /// Cycle closed by calculating `head` again.
+    pub fn cycle(&self, head: &DepIdx) -> Cycle {
+        Cycle::new(self.path(head))
+    }
+
+    /// Queries from `from` to the innermost one, the whole stack if `from` isn't on it.
+    pub fn path(&self, from: &DepIdx) -> Vec<QueryInfo> {
+        let mut queries = vec![];
+        for frame in self.frames() {
+            queries.push(frame.info());
+            if frame.idx == *from {
+                break;
+            }
+        }
+        queries.reverse();
+        queries
+    }
+
+    /// Any of the queries on the stack is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.frames()
+            .any(|frame| frame.cancelled.load(Ordering::SeqCst))
+    }
+
+    /// Cancels the innermost query along with the ones waiting for it.
+    pub fn cancel(&self) {
+        for frame in self.frames() {
+            frame.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Frame {
+    fn info(&self) -> QueryInfo {
+        QueryInfo {
+            type_name: self.idx.query_name,
+            key: format!("{:?}", self.query),
+        }
+    }
+}
+
+/// Frames of queries being calculated right now.
+#[derive(Default)]
+pub(crate) struct Frames {
+    live: Mutex<Vec<Weak<Frame>>>,
+}
+
+impl Frames {
+    fn register(&self, frame: &Arc<Frame>) {
+        let mut live = self.live.lock().expect("Frames lock");
+        live.retain(|frame| frame.strong_count() > 0);
+        live.push(Arc::downgrade(frame));
+    }
+
+    pub fn live(&self) -> Vec<QueryStack> {
+        let live = self.live.lock().expect("Frames lock");
+        live.iter()
+            .filter_map(Weak::upgrade)
+            .map(|frame| QueryStack(Some(frame)))
+            .collect()
+    }
+}
+
+/// Fork started by [`System::fork`](crate::System::fork), on top of the queries it was forked from.
+pub(crate) struct ForkHandle {
+    fork: Weak<ForkInfo>,
+    stack: QueryStack,
+    handle: AbortHandle,
+}
+
+impl ForkHandle {
+    pub fn new(fork: &Runtime, handle: AbortHandle) -> Self {
+        Self {
+            fork: Arc::downgrade(&fork.fork),
+            stack: fork.stack.clone(),
+            handle,
+        }
+    }
+
+    pub fn abort(&self) {
+        self.handle.abort();
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.fork.strong_count() == 0
+    }
+}
+
+impl Runtime {
+    /// Forks that haven't completed yet, with the queries they are calculating.
+    pub async fn in_flight(&self) -> Vec<InFlight> {
+        let frames = self.frames.live();
+        let mut handles = self.handles.write().await;
+        handles.retain(|handle| !handle.is_completed());
+
+        let forks = handles.iter().filter_map(|handle| handle.fork.upgrade());
+        forks
+            .map(|fork| {
+                // Frames are registered in order, the innermost one is the last.
+                let query = frames
+                    .iter()
+                    .rev()
+                    .filter_map(|stack| stack.0.as_ref())
+                    .find(|frame| frame.fork == fork.id)
+                    .map(|frame| frame.info());
+                InFlight {
+                    fork: fork.id,
+                    started_at: fork.started_at,
+                    query,
+                }
+            })
+            .collect()
+    }
+
+    /// Cancels queries that have read any of the `changed` inputs so far, along with the queries
+    /// waiting for them. Only forks calculating cancelled queries are aborted.
+    pub(crate) async fn cancel_affected(&self, changed: &HashSet<(TypeId, usize)>) {
+        if changed.is_empty() {
+            return;
+        }
+
+        let frames = self.frames.live();
+        let mut unaffected = HashSet::new();
+        for stack in &frames {
+            // Cancelled along with a query waiting for it already.
+            if stack.is_cancelled() {
+                continue;
+            }
+            let frame = stack.0.as_ref().expect("Live frame");
+            let tracked = match frame.tracked.upgrade() {
+                Some(tracked) => tracked,
+                None => continue,
+            };
+            let deps = tracked.read().await.deps.clone();
+            if self.depends_on(deps, changed, &mut unaffected) {
+                tracing::debug!("Canceling {:?}", frame.query);
+                stack.cancel();
+            }
+        }
+        self.abort_cancelled(&frames).await;
+    }
+
+    /// Aborts forks calculating cancelled queries, or forked from them.
+    pub(crate) async fn abort_cancelled(&self, frames: &[QueryStack]) {
+        let cancelled: HashSet<ForkId> = frames
+            .iter()
+            .filter(|stack| stack.is_cancelled())
+            .filter_map(|stack| stack.0.as_ref().map(|frame| frame.fork))
+            .collect();
+        let mut handles = self.handles.write().await;
+        handles.retain(|handle| {
+            let fork = match handle.fork.upgrade() {
+                Some(fork) => fork,
+                None => return false,
+            };
+            let cancel = cancelled.contains(&fork.id) || handle.stack.is_cancelled();
+            if cancel {
+                tracing::debug!("Canceling fork {:?}", fork.id);
+                handle.abort();
+            }
+            !cancel
+        });
+    }
+
+    /// Any of the dependencies has read any of the `changed` inputs, directly or through its
+    /// own ones. Walks stop at the first one found, cells known to reach none of them are kept
+    /// in `unaffected` for the next walks.
+    fn depends_on(
+        &self,
+        mut deps: Vec<Dep>,
+        changed: &HashSet<(TypeId, usize)>,
+        unaffected: &mut HashSet<(TypeId, usize)>,
+    ) -> bool {
+        let mut visited = vec![];
+        while let Some(dep) = deps.pop() {
+            let key = (dep.query_type(), dep.idx.query_idx);
+            if changed.contains(&key) {
+                // Cells visited on the way could still reach others, it's not known yet.
+                for key in visited {
+                    unaffected.remove(&key);
+                }
+                return true;
+            }
+            // Cycles don't reach anything new.
+            if !unaffected.insert(key) {
+                continue;
+            }
+            visited.push(key);
+
+            let storage = self.queries.get(dep.query_type()).expect("Dep storage");
+            deps.extend(storage.deps(&dep).iter().cloned());
+        }
+        false
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tracks when each cell of a storage was used last, and which cells hold outputs.
+/// Capacity `0` means the storage is unbounded and nothing gets evicted.
+pub(crate) struct Lru {
+    capacity: usize,
+    clock: AtomicUsize,
+    used: Vec<AtomicUsize>,
+    /// Cells holding outputs by when they were used, as of when they were queued.
+    /// Touches only update `used`, the queue catches up with them when evicting.
+    queue: BTreeMap<usize, usize>,
+    /// When each cell was used as of its place in the queue, if it's queued.
+    queued: Vec<Option<usize>>,
+}
+
+impl Lru {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clock: Default::default(),
+            used: Default::default(),
+            queue: Default::default(),
+            queued: Default::default(),
+        }
+    }
+
+    pub fn push(&mut self) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        self.used.push(AtomicUsize::new(now));
+        self.queued.push(None);
+    }
+
+    pub fn touch(&self, idx: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        self.used[idx].store(now, Ordering::Relaxed);
+    }
+
+    /// The cell holds an output now.
+    pub fn calculated(&mut self, idx: usize) {
+        if self.capacity == 0 || self.queued[idx].is_some() {
+            return;
+        }
+        self.enqueue(idx);
+    }
+
+    /// The cell doesn't hold an output any more.
+    pub fn discarded(&mut self, idx: usize) {
+        if let Some(used) = self.queued[idx].take() {
+            self.queue.remove(&used);
+        }
+    }
+
+    /// More cells hold outputs than the capacity allows.
+    pub fn is_over_capacity(&self) -> bool {
+        self.capacity != 0 && self.queue.len() > self.capacity
+    }
+
+    /// Dequeues the least recently used cell holding an output, other than `keep`.
+    pub fn evict(&mut self, keep: usize) -> Option<usize> {
+        let mut kept = false;
+        let evicted = loop {
+            let (queued_at, idx) = match self.queue.pop_first() {
+                Some(entry) => entry,
+                None => break None,
+            };
+            self.queued[idx] = None;
+            if idx == keep {
+                kept = true;
+            } else if self.used[idx].load(Ordering::Relaxed) != queued_at {
+                // Used since it was queued.
+                self.enqueue(idx);
+            } else {
+                break Some(idx);
+            }
+        };
+        if kept {
+            self.enqueue(keep);
+        }
+        evicted
+    }
+
+    fn enqueue(&mut self, idx: usize) {
+        let used = self.used[idx].load(Ordering::Relaxed);
+        self.queue.insert(used, idx);
+        self.queued[idx] = Some(used);
+    }
+}
+
+impl Clone for Lru {
+    fn clone(&self) -> Self {
+        let load = |counter: &AtomicUsize| AtomicUsize::new(counter.load(Ordering::Relaxed));
+        Self {
+            capacity: self.capacity,
+            clock: load(&self.clock),
+            used: self.used.iter().map(load).collect(),
+            queue: self.queue.clone(),
+            queued: self.queued.clone(),
+        }
+    }
+}
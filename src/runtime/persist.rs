@@ -0,0 +1,302 @@
+use super::storage::{LockedStorage, QueryStorage, Stamps, Storage};
+use super::{Dep, DepIdx, Runtime};
+use crate::{Durability, Intern, Interned, Query, Revision, Try, TryQuery};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+type SaveFn = fn(Arc<dyn Storage>, &Dropped) -> io::Result<Vec<u8>>;
+type LoadFn = fn(&[u8], &Persist) -> io::Result<Arc<dyn Storage>>;
+type UnsavedFn = fn(Arc<dyn Storage>, &Persist, &Dropped) -> Vec<usize>;
+
+/// Cells saved without their keys, they can't be validated after load.
+pub(super) type Dropped = HashSet<(TypeId, usize)>;
+
+struct Persister {
+    name: &'static str,
+    save: SaveFn,
+    load: LoadFn,
+    unsaved: UnsavedFn,
+}
+
+thread_local! {
+    /// Query types persisted by the `Runtime::save` running on this thread.
+    static SAVING: RefCell<Option<HashSet<TypeId>>> = const { RefCell::new(None) };
+}
+
+/// Ids of interned values are valid only if the values are saved too.
+pub(crate) fn is_interned_persisted<T: Intern>() -> bool {
+    SAVING.with(|saving| match &*saving.borrow() {
+        Some(persisted) => persisted.contains(&TypeId::of::<Interned<T>>()),
+        None => true,
+    })
+}
+
+/// Persist is a list of query types saved by `Runtime::save` and restored by `Runtime::load`.
+/// Types are matched by their type name, so the file should be loaded by the same build.
+/// Cells depending on a query type that is not on the list are not restored, neither are their
+/// dependents. They will be recalculated.
+#[derive(Default)]
+pub struct Persist {
+    persisters: HashMap<TypeId, Persister>,
+}
+
+impl Persist {
+    pub fn with<Q>(mut self) -> Self
+    where
+        Q: Query + Serialize + DeserializeOwned,
+        Q::Output: Serialize + DeserializeOwned,
+    {
+        let persister = Persister {
+            name: std::any::type_name::<Q>(),
+            save: save_storage::<Q>,
+            load: load_storage::<Q>,
+            unsaved: unsaved_cells::<Q>,
+        };
+        self.persisters.insert(TypeId::of::<Q>(), persister);
+        self
+    }
+
+    /// Interned values have to be persisted along with queries that use their ids.
+    pub fn with_interned<T>(self) -> Self
+    where
+        T: Intern + Serialize + DeserializeOwned,
+    {
+        self.with::<Interned<T>>()
+    }
+
+    pub fn with_try<Q>(self) -> Self
+    where
+        Q: TryQuery + Serialize + DeserializeOwned,
+        Q::Ok: Serialize + DeserializeOwned,
+        Q::Error: Serialize + DeserializeOwned,
+    {
+        self.with::<Try<Q>>()
+    }
+
+    /// The cell can be validated after load if it depends only on restored cells.
+    pub(super) fn is_restored(&self, dep: &DepIdx, dropped: &Dropped) -> bool {
+        self.persisters.contains_key(&dep.query_type)
+            && !dropped.contains(&(dep.query_type, dep.query_idx))
+    }
+
+    fn find(&self, name: &str) -> Option<(TypeId, &Persister)> {
+        self.persisters
+            .iter()
+            .find(|(_, persister)| persister.name == name)
+            .map(|(&type_id, persister)| (type_id, persister))
+    }
+}
+
+fn downcast<Q: Query>(storage: Arc<dyn Storage>) -> Arc<LockedStorage<Q>> {
+    storage
+        .into_any()
+        .downcast::<LockedStorage<Q>>()
+        .unwrap_or_else(|_| panic!("Couldn't downcast to storage"))
+}
+
+fn save_storage<Q>(storage: Arc<dyn Storage>, dropped: &Dropped) -> io::Result<Vec<u8>>
+where
+    Q: Query + Serialize,
+    Q::Output: Serialize,
+{
+    let storage = downcast::<Q>(storage);
+    let storage = storage.read();
+    let data = storage.save_data(dropped);
+    bincode::serialize(&data).map_err(invalid_data)
+}
+
+fn unsaved_cells<Q: Query>(
+    storage: Arc<dyn Storage>,
+    persist: &Persist,
+    dropped: &Dropped,
+) -> Vec<usize> {
+    downcast::<Q>(storage).read().unsaved(persist, dropped)
+}
+
+fn load_storage<Q>(bytes: &[u8], persist: &Persist) -> io::Result<Arc<dyn Storage>>
+where
+    Q: Query + DeserializeOwned,
+    Q::Output: DeserializeOwned,
+{
+    let data: Vec<CellData<Option<Q>, Q::Output>> =
+        bincode::deserialize(bytes).map_err(invalid_data)?;
+    let storage = QueryStorage::<Q>::load_data(data, persist)?;
+    Ok(Arc::new(LockedStorage::new(storage)))
+}
+
+fn invalid_data(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct CellData<K, O> {
+    /// Key is `None` when the query was removed.
+    pub key: K,
+    /// Output is `None` when it wasn't calculated, it'll be recalculated after load.
+    pub output: Option<O>,
+    pub stamps: Stamps,
+    pub durability: Durability,
+    pub deps: Vec<DepData>,
+    pub heads: Vec<HeadData>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct DepData {
+    query: String,
+    idx: usize,
+    rev: Revision,
+    durability: Durability,
+}
+
+impl DepData {
+    pub fn save(dep: &Dep) -> Self {
+        Self {
+            query: dep.idx.query_name.to_string(),
+            idx: dep.idx.query_idx,
+            rev: dep.query_rev,
+            durability: dep.durability,
+        }
+    }
+
+    pub fn load(self, persist: &Persist) -> io::Result<Dep> {
+        let (query_type, persister) = persist
+            .find(&self.query)
+            .ok_or_else(|| invalid_data(UnknownQuery(self.query.clone())))?;
+
+        Ok(Dep {
+            idx: DepIdx {
+                query_name: persister.name,
+                query_type,
+                query_idx: self.idx,
+            },
+            query_rev: self.rev,
+            durability: self.durability,
+        })
+    }
+}
+
+/// Head of a cycle the cell is part of.
+#[derive(Serialize, Deserialize)]
+pub(super) struct HeadData {
+    query: String,
+    idx: usize,
+}
+
+impl HeadData {
+    pub fn save(head: &DepIdx) -> Self {
+        Self {
+            query: head.query_name.to_string(),
+            idx: head.query_idx,
+        }
+    }
+
+    pub fn load(self, persist: &Persist) -> io::Result<DepIdx> {
+        let (query_type, persister) = persist
+            .find(&self.query)
+            .ok_or_else(|| invalid_data(UnknownQuery(self.query.clone())))?;
+
+        Ok(DepIdx {
+            query_name: persister.name,
+            query_type,
+            query_idx: self.idx,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct UnknownQuery(String);
+
+impl std::fmt::Display for UnknownQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Query {} is not persisted", self.0)
+    }
+}
+
+impl std::error::Error for UnknownQuery {}
+
+#[derive(Serialize, Deserialize)]
+struct Database {
+    rev: Revision,
+    last_changed: Vec<Revision>,
+    storages: Vec<(String, Vec<u8>)>,
+}
+
+impl Runtime {
+    /// Saves inputs, outputs and dependencies of persisted query types into a file.
+    /// Fails if any of them can't be serialized, or ids of interned values are saved without
+    /// the values. Blocks on writing the file.
+    pub fn save(&self, persist: &Persist, path: impl AsRef<Path>) -> io::Result<()> {
+        let persisted: Vec<_> = self
+            .queries
+            .all()
+            .into_iter()
+            .filter_map(|(type_id, storage)| {
+                let persister = persist.persisters.get(&type_id)?;
+                Some((type_id, persister, storage))
+            })
+            .collect();
+
+        // Cells depending on dropped ones can't be validated either.
+        let mut dropped = Dropped::new();
+        loop {
+            let mut unsaved = vec![];
+            for (type_id, persister, storage) in &persisted {
+                let cells = (persister.unsaved)(storage.clone(), persist, &dropped);
+                unsaved.extend(cells.into_iter().map(|idx| (*type_id, idx)));
+            }
+            if unsaved.is_empty() {
+                break;
+            }
+            dropped.extend(unsaved);
+        }
+
+        let types = persist.persisters.keys().copied().collect();
+        SAVING.with(|saving| *saving.borrow_mut() = Some(types));
+        let storages = persisted
+            .into_iter()
+            .map(|(_, persister, storage)| {
+                let bytes = (persister.save)(storage, &dropped)?;
+                Ok((persister.name.to_string(), bytes))
+            })
+            .collect::<io::Result<Vec<_>>>();
+        SAVING.with(|saving| *saving.borrow_mut() = None);
+        let storages = storages?;
+
+        let database = Database {
+            rev: self.current_rev(),
+            last_changed: self.last_changed.all(),
+            storages,
+        };
+
+        let bytes = bincode::serialize(&database).map_err(invalid_data)?;
+        std::fs::write(path, bytes)
+    }
+
+    /// Creates a runtime out of a file written by `Runtime::save`.
+    /// Restored queries are validated on first use instead of being recalculated.
+    /// Blocks on reading the file.
+    pub fn load(persist: &Persist, path: impl AsRef<Path>) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let database: Database = bincode::deserialize(&bytes).map_err(invalid_data)?;
+
+        let runtime = Self::default();
+        for (name, bytes) in database.storages {
+            let (type_id, persister) = persist
+                .find(&name)
+                .ok_or_else(|| invalid_data(UnknownQuery(name.clone())))?;
+            let storage = (persister.load)(&bytes, persist)?;
+            runtime.queries.insert(type_id, storage);
+        }
+
+        database.rev.restore(&runtime.rev_counter);
+        runtime.last_changed.restore(&database.last_changed);
+
+        Ok(runtime)
+    }
+}
@@ -1,15 +1,40 @@
-use crate::runtime::Dep;
-use crate::{Query, QueryRef, Runtime, System};
+use crate::runtime::{Dep, DepIdx};
+use crate::{
+    Cancelled, Forked, Intern, InternId, Interned, JoinHandle, NoSpawner, Query, QueryRef,
+    Runtime, System,
+};
 use async_trait::async_trait;
-use futures::future::{abortable, Abortable};
+use futures::future::{self, poll_fn, AbortHandle};
+use futures::task::Poll;
 use futures::Future;
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+/// What a query has read during its calculation.
+#[derive(Debug, Default)]
+pub(crate) struct Tracked {
+    pub deps: Vec<Dep>,
+    /// When each dep started to be read, deps read concurrently are kept in the order they
+    /// were requested in. Deps read by forks follow the reads before the fork was created.
+    order: Vec<Vec<usize>>,
+    /// Cycle heads of provisional outputs that were read.
+    pub pending: Vec<DepIdx>,
+}
+
 pub(super) struct QueryTracker {
     runtime: Runtime,
-    deps: Arc<RwLock<Vec<Dep>>>,
+    tracked: Arc<RwLock<Tracked>>,
+    reads: Arc<Reads>,
+}
+
+/// Reads of a query, or of one of its forks.
+#[derive(Default)]
+struct Reads {
+    started: AtomicUsize,
+    /// Read that created the fork, nested forks included.
+    forked_at: Vec<usize>,
 }
 
 impl fmt::Debug for QueryTracker {
@@ -19,31 +44,81 @@ impl fmt::Debug for QueryTracker {
 }
 
 impl QueryTracker {
-    pub fn new(runtime: &Runtime) -> Self {
+    /// Tracks calculation of the query at `idx`, it's pushed to the query stack.
+    pub fn new<Q: Query>(runtime: &Runtime, idx: usize, query: &Arc<Q>) -> Self {
+        let mut runtime = runtime.fork_no_inc();
+        let tracked = Default::default();
+        let idx = DepIdx::of::<Q>(idx);
+        let (frames, fork) = (runtime.frames.clone(), runtime.fork.id);
+        runtime.stack = runtime.stack.push(&frames, idx, query.clone(), fork, &tracked);
         Self {
-            runtime: runtime.fork_no_inc(),
-            deps: Default::default(),
+            runtime,
+            tracked,
+            reads: Default::default(),
         }
     }
 
-    pub fn into_deps(self) -> Vec<Dep> {
-        Arc::try_unwrap(self.deps).unwrap().into_inner()
+    /// Calculates the query, the calculation is dropped as soon as the query is cancelled.
+    pub fn calc<'a, Q: Query>(
+        &'a self,
+        query: &'a Q,
+    ) -> impl Future<Output = Result<Q::Output, Cancelled>> + 'a {
+        let stack = &self.runtime.stack;
+        let mut calc = query.calc(self);
+        poll_fn(move |cx| {
+            if stack.is_cancelled() {
+                return Poll::Ready(Err(Cancelled));
+            }
+            match calc.as_mut().poll(cx) {
+                Poll::Ready(output) => Poll::Ready(Ok(output)),
+                Poll::Pending if stack.is_cancelled() => Poll::Ready(Err(Cancelled)),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+    }
+
+    /// Aborted forks of the query could still be around, what they read is left out.
+    pub async fn into_tracked(self) -> Tracked {
+        std::mem::take(&mut *self.tracked.write().await)
+    }
+
+    fn start_read(&self) -> usize {
+        self.reads.started.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Orders the read after the ones of the query and its forks started before it.
+    fn read_order(&self, read: usize) -> Vec<usize> {
+        let forked_at = self.reads.forked_at.iter().copied();
+        forked_at.chain(std::iter::once(read)).collect()
     }
 
-    #[tracing::instrument(skip(dep))]
-    async fn add_dep(&self, dep: Dep) {
+    #[tracing::instrument(skip(dep, pending))]
+    async fn add_dep(&self, read: usize, dep: Dep, pending: &[DepIdx]) {
         tracing::trace!("WRITE RW LOCK");
-        self.deps.write().await.push(dep);
+        let mut tracked = self.tracked.write().await;
+        let read = self.read_order(read);
+        let at = tracked.order.partition_point(|started| *started < read);
+        tracked.order.insert(at, read);
+        tracked.deps.insert(at, dep);
+        for head in pending {
+            if !tracked.pending.contains(head) {
+                tracked.pending.push(*head);
+            }
+        }
     }
 }
 
 #[async_trait]
 impl System for QueryTracker {
     async fn query_ref<Q: Query>(&self, query: Q) -> QueryRef<<Q as Query>::Output> {
-        let cell = self.runtime.query_inner(query).await;
+        let read = self.start_read();
+        let cell = match self.runtime.query_inner(query).await {
+            Ok(cell) => cell,
+            Err(cancelled) => return self.propagate(cancelled).await,
+        };
 
         let dep = cell.as_dep();
-        self.add_dep(dep).await;
+        self.add_dep(read, dep, cell.pending()).await;
         QueryRef(cell.output().unwrap())
     }
 
@@ -52,27 +127,91 @@ impl System for QueryTracker {
         Q: Query,
         Q::Output: Clone,
     {
-        let cell = self.runtime.query_inner(query).await;
+        let read = self.start_read();
+        let cell = match self.runtime.query_inner(query).await {
+            Ok(cell) => cell,
+            Err(cancelled) => return self.propagate(cancelled).await,
+        };
 
         let dep = cell.as_dep();
-        self.add_dep(dep).await;
+        self.add_dep(read, dep, cell.pending()).await;
 
         let output = cell.output().unwrap();
         (*output).clone()
     }
 
-    async fn fork<F, Fut>(&self, f: F) -> Abortable<Fut>
+    async fn query_cancellable<Q>(&self, query: Q) -> Result<Q::Output, Cancelled>
+    where
+        Q: Query,
+        Q::Output: Clone,
+    {
+        let read = self.start_read();
+        let cell = self.runtime.query_inner(query).await?;
+
+        let dep = cell.as_dep();
+        self.add_dep(read, dep, cell.pending()).await;
+
+        let output = cell.output().unwrap();
+        Ok((*output).clone())
+    }
+
+    async fn intern<T: Intern>(&self, value: T) -> InternId<T> {
+        let read = self.start_read();
+        let cell = match self.runtime.query_inner(Interned(value)).await {
+            Ok(cell) => cell,
+            Err(cancelled) => return self.propagate(cancelled).await,
+        };
+
+        let dep = cell.as_dep();
+        self.add_dep(read, dep, &[]).await;
+        InternId::new(cell.idx())
+    }
+
+    async fn lookup<T: Intern>(&self, id: InternId<T>) -> T {
+        let read = self.start_read();
+        let (value, cell) = self.runtime.lookup_inner(id).await;
+
+        let dep = cell.as_dep();
+        self.add_dep(read, dep, &[]).await;
+        value
+    }
+
+    async fn fork<F, Fut>(&self, f: F) -> Forked<Fut>
     where
         F: Send + Fn(Self) -> Fut,
         Fut: Send + Future,
     {
         let fork = Self {
             runtime: self.runtime.fork_inner(),
-            deps: self.deps.clone(),
+            tracked: self.tracked.clone(),
+            reads: Arc::new(Reads {
+                started: Default::default(),
+                forked_at: self.read_order(self.start_read()),
+            }),
         };
-        let fut = f(fork);
-        let (fut, handle) = abortable(fut);
-        self.runtime.handles.write().await.push(handle);
-        fut
+        let (handle, registration) = AbortHandle::new_pair();
+        self.runtime.register_fork(&fork.runtime, handle).await;
+        Forked::new(f(fork), registration)
+    }
+
+    async fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T::Output>, NoSpawner>
+    where
+        F: Send + Fn(Self) -> T,
+        T: Future + Send + 'static,
+        T::Output: Send + 'static,
+    {
+        let forked = self.fork(f).await;
+        self.runtime.spawner.spawn(forked)
+    }
+
+    async fn propagate<T: Send>(&self, _cancelled: Cancelled) -> T {
+        // Cancels the query, its calculation is dropped before it's polled again.
+        self.runtime.stack.cancel();
+        self.runtime.abort_cancelled(&self.runtime.frames.live()).await;
+        future::pending().await
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.runtime.is_cancelled()
     }
 }
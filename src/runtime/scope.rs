@@ -0,0 +1,142 @@
+use super::{ForkHandle, Runtime};
+use crate::{Cancelled, Forked, Intern, InternId, JoinHandle, NoSpawner, Query, QueryRef, System};
+use async_trait::async_trait;
+use futures::future::AbortHandle;
+use futures::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Owns the forks started under it, nested ones included, like the work of one request.
+/// They are canceled along with the scope, or when it's dropped.
+#[derive(Debug)]
+pub struct Scope {
+    runtime: Runtime,
+    /// Only the scope returned by [`Runtime::scope`] cancels it on drop, not its forks.
+    owner: bool,
+}
+
+impl Scope {
+    /// Aborts all forks of the scope, queries calculated under it see they are
+    /// [cancelled](System::is_cancelled).
+    pub fn cancel(&self) {
+        if let Some(state) = &self.runtime.scope {
+            state.cancel();
+        }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        if self.owner {
+            self.cancel();
+        }
+    }
+}
+
+#[async_trait]
+impl System for Scope {
+    async fn query_ref<Q: Query>(&self, query: Q) -> QueryRef<Q::Output> {
+        self.runtime.query_ref(query).await
+    }
+
+    async fn query<Q>(&self, query: Q) -> Q::Output
+    where
+        Q: Query,
+        Q::Output: Clone,
+    {
+        self.runtime.query(query).await
+    }
+
+    async fn query_cancellable<Q>(&self, query: Q) -> Result<Q::Output, Cancelled>
+    where
+        Q: Query,
+        Q::Output: Clone,
+    {
+        self.runtime.query_cancellable(query).await
+    }
+
+    async fn intern<T: Intern>(&self, value: T) -> InternId<T> {
+        self.runtime.intern(value).await
+    }
+
+    async fn lookup<T: Intern>(&self, id: InternId<T>) -> T {
+        self.runtime.lookup(id).await
+    }
+
+    async fn propagate<T: Send>(&self, cancelled: Cancelled) -> T {
+        self.runtime.propagate(cancelled).await
+    }
+
+    async fn fork<F, Fut>(&self, f: F) -> Forked<Fut>
+    where
+        F: Send + Fn(Self) -> Fut,
+        Fut: Future + Send,
+    {
+        let fork = Self {
+            runtime: self.runtime.fork_inner(),
+            owner: false,
+        };
+        let (handle, registration) = AbortHandle::new_pair();
+        self.runtime.register_fork(&fork.runtime, handle).await;
+
+        Forked::new(f(fork), registration)
+    }
+
+    async fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T::Output>, NoSpawner>
+    where
+        F: Send + Fn(Self) -> T,
+        T: Future + Send + 'static,
+        T::Output: Send + 'static,
+    {
+        let forked = self.fork(f).await;
+        self.runtime.spawner.spawn(forked)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.runtime.is_cancelled()
+    }
+}
+
+/// Forks started in a scope, shared by all of them.
+#[derive(Default)]
+pub(crate) struct ScopeState {
+    cancelled: AtomicBool,
+    handles: Mutex<Vec<ForkHandle>>,
+}
+
+impl ScopeState {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Forks started after the scope is canceled are aborted right away.
+    pub fn push(&self, handle: ForkHandle) {
+        let mut handles = self.handles.lock().expect("Scope handles lock");
+        if self.is_cancelled() {
+            handle.abort();
+        }
+        handles.retain(|handle| !handle.is_completed());
+        handles.push(handle);
+    }
+
+    fn cancel(&self) {
+        let mut handles = self.handles.lock().expect("Scope handles lock");
+        self.cancelled.store(true, Ordering::SeqCst);
+        for handle in handles.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+impl Runtime {
+    /// Starts a scope for forks that can be canceled together, without touching inputs.
+    pub fn scope(&self) -> Scope {
+        let mut runtime = self.fork_inner();
+        runtime.scope = Some(Default::default());
+
+        Scope {
+            runtime,
+            owner: true,
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use super::{ForkHandle, ForkInfo, Runtime};
+use crate::{
+    Cancelled, Forked, Intern, InternId, JoinHandle, NoSpawner, Query, QueryRef, Revision, System,
+};
+use async_trait::async_trait;
+use futures::future::AbortHandle;
+use futures::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use tokio::sync::{Notify, RwLock};
+
+/// What a write does with snapshots taken before it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SnapshotPolicy {
+    /// Waits until all snapshots, along with their forks, are dropped.
+    Wait,
+    /// Aborts forks of the snapshots, they still see the old revision.
+    #[default]
+    Cancel,
+    /// Snapshots keep working on the old revision.
+    Ignore,
+}
+
+/// Read-only view of the runtime pinned to the revision it was taken at.
+/// Queries see inputs as of that revision, even if newer ones are set concurrently.
+#[derive(Debug)]
+pub struct Snapshot {
+    runtime: Runtime,
+}
+
+impl Snapshot {
+    pub fn revision(&self) -> Revision {
+        self.runtime.current_rev()
+    }
+
+    pub async fn query_rev<Q>(&self, query: Q) -> Result<(Q::Output, Revision), Cancelled>
+    where
+        Q: Query,
+        Q::Output: Clone,
+    {
+        self.runtime.query_rev(query).await
+    }
+}
+
+#[async_trait]
+impl System for Snapshot {
+    async fn query_ref<Q: Query>(&self, query: Q) -> QueryRef<Q::Output> {
+        self.runtime.query_ref(query).await
+    }
+
+    async fn query<Q>(&self, query: Q) -> Q::Output
+    where
+        Q: Query,
+        Q::Output: Clone,
+    {
+        self.runtime.query(query).await
+    }
+
+    async fn query_cancellable<Q>(&self, query: Q) -> Result<Q::Output, Cancelled>
+    where
+        Q: Query,
+        Q::Output: Clone,
+    {
+        self.runtime.query_cancellable(query).await
+    }
+
+    async fn intern<T: Intern>(&self, value: T) -> InternId<T> {
+        self.runtime.intern(value).await
+    }
+
+    async fn lookup<T: Intern>(&self, id: InternId<T>) -> T {
+        self.runtime.lookup(id).await
+    }
+
+    async fn propagate<T: Send>(&self, cancelled: Cancelled) -> T {
+        self.runtime.propagate(cancelled).await
+    }
+
+    async fn fork<F, Fut>(&self, f: F) -> Forked<Fut>
+    where
+        F: Send + Fn(Self) -> Fut,
+        Fut: Future + Send,
+    {
+        let fork = Self {
+            runtime: self.runtime.fork_inner(),
+        };
+        let (handle, registration) = AbortHandle::new_pair();
+        self.runtime.register_fork(&fork.runtime, handle).await;
+
+        Forked::new(f(fork), registration)
+    }
+
+    async fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T::Output>, NoSpawner>
+    where
+        F: Send + Fn(Self) -> T,
+        T: Future + Send + 'static,
+        T::Output: Send + 'static,
+    {
+        let forked = self.fork(f).await;
+        self.runtime.spawner.spawn(forked)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.runtime.is_cancelled()
+    }
+}
+
+/// Kept by every runtime of a snapshot, the snapshot is outstanding until all of them are dropped.
+pub(crate) struct SnapshotGuard {
+    handles: Arc<RwLock<Vec<ForkHandle>>>,
+    snapshots: Arc<Snapshots>,
+    cancelled: AtomicBool,
+}
+
+impl SnapshotGuard {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        self.snapshots.dropped.notify();
+    }
+}
+
+/// Snapshots taken from a runtime and the policy its writes follow.
+#[derive(Default)]
+pub(crate) struct Snapshots {
+    policy: Mutex<SnapshotPolicy>,
+    live: Mutex<Vec<Weak<SnapshotGuard>>>,
+    dropped: Notify,
+    /// Snapshots are never taken in the middle of a write.
+    writing: Mutex<()>,
+}
+
+impl Snapshots {
+    pub fn policy(&self) -> SnapshotPolicy {
+        *self.policy.lock().expect("Snapshot policy lock")
+    }
+
+    pub fn set_policy(&self, policy: SnapshotPolicy) {
+        *self.policy.lock().expect("Snapshot policy lock") = policy;
+    }
+
+    pub fn writing(&self) -> MutexGuard<'_, ()> {
+        self.writing.lock().expect("Snapshot writing lock")
+    }
+
+    fn live(&self) -> Vec<Arc<SnapshotGuard>> {
+        let mut live = self.live.lock().expect("Snapshot live lock");
+        live.retain(|guard| guard.strong_count() > 0);
+        live.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    pub fn register(
+        self: &Arc<Self>,
+        handles: Arc<RwLock<Vec<ForkHandle>>>,
+    ) -> Arc<SnapshotGuard> {
+        let guard = Arc::new(SnapshotGuard {
+            handles,
+            snapshots: self.clone(),
+            cancelled: AtomicBool::new(false),
+        });
+        let mut live = self.live.lock().expect("Snapshot live lock");
+        live.push(Arc::downgrade(&guard));
+        guard
+    }
+
+    pub async fn before_write(&self) {
+        match self.policy() {
+            SnapshotPolicy::Wait => {
+                while !self.live().is_empty() {
+                    tracing::debug!("Waiting for snapshots");
+                    self.dropped.notified().await;
+                }
+            }
+            SnapshotPolicy::Cancel => {
+                for guard in self.live() {
+                    tracing::debug!("Canceling snapshot");
+                    guard.cancelled.store(true, Ordering::SeqCst);
+                    for fork in guard.handles.write().await.drain(..) {
+                        fork.abort();
+                    }
+                }
+            }
+            SnapshotPolicy::Ignore => (),
+        }
+    }
+
+    /// No snapshot is left to deal with, checked under the `writing` lock.
+    pub fn is_ready_for_write(&self) -> bool {
+        match self.policy() {
+            SnapshotPolicy::Wait => self.live().is_empty(),
+            SnapshotPolicy::Cancel => self
+                .live()
+                .iter()
+                .all(|guard| guard.cancelled.load(Ordering::SeqCst)),
+            SnapshotPolicy::Ignore => true,
+        }
+    }
+}
+
+impl Runtime {
+    /// Number of query types whose cells are still shared with snapshots,
+    /// cells are copied only when they're written.
+    pub fn shared_storages(&self) -> usize {
+        self.queries.shared()
+    }
+
+    /// Takes a snapshot of the current revision.
+    /// Writes follow the [`SnapshotPolicy`] for snapshots that are still around.
+    pub fn snapshot(&self) -> Snapshot {
+        let _writing = self.snapshots.writing();
+
+        let handles: Arc<RwLock<Vec<ForkHandle>>> = Default::default();
+        let guard = self.snapshots.register(handles.clone());
+        let runtime = Runtime {
+            queries: Arc::new(self.queries.pinned()),
+            handles,
+            rev_counter: Revision::pin(&self.rev_counter),
+            last_changed: Arc::new(self.last_changed.pinned()),
+            fork_counter: self.fork_counter.clone(),
+            fork: Arc::new(ForkInfo {
+                id: crate::ForkId::new(&self.fork_counter),
+                started_at: self.current_rev(),
+            }),
+            snapshots: self.snapshots.clone(),
+            snapshot: Some(guard),
+            scope: None,
+            stack: Default::default(),
+            waits: self.waits.clone(),
+            frames: Default::default(),
+            spawner: self.spawner.clone(),
+        };
+
+        Snapshot { runtime }
+    }
+
+    pub fn set_snapshot_policy(&self, policy: SnapshotPolicy) {
+        self.snapshots.set_policy(policy);
+    }
+}
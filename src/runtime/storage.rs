@@ -1,16 +1,21 @@
-use crate::runtime::dep::{Dep, DepIdx};
-use crate::{DynQuery, ForkId, Invalidation, Query, Revision, Runtime, ReservationReader, Reservation};
+use crate::runtime::dep::{Dep, DepIdx, DepsExt};
+use crate::runtime::lru::Lru;
+use crate::runtime::query_tracker::{QueryTracker, Tracked};
+use crate::{Cancelled, Cycle, Durability, DynQuery, ForkId, Invalidation, Query, Revision, Runtime, ReservationReader, Reservation};
 use async_trait::async_trait;
 use std::any::{Any, TypeId};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::sync::Arc;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 
 #[derive(Clone)]
 pub(crate) enum CycleDetection {
     CycleDetected,
-    Locked(ReservationReader),
+    Locked(ForkId, ReservationReader),
+    /// Reservation was dropped before the output was stored, the fork was dropped or panicked.
+    Abandoned,
     Canceled,
     Ok
 }
@@ -19,6 +24,8 @@ pub(crate) enum CycleDetection {
 pub(crate) enum QueryOutput<Q: Query> {
     Calculating(ForkId, Revision, ReservationReader),
     Calculated(Arc<Q::Output>),
+    Evicted,
+    Removed,
 }
 
 impl<Q: Query> Clone for QueryOutput<Q> {
@@ -26,6 +33,8 @@ impl<Q: Query> Clone for QueryOutput<Q> {
         match self {
             Self::Calculated(out) => Self::Calculated(out.clone()),
             Self::Calculating(fork, rev, lock) => Self::Calculating(*fork, *rev, lock.clone()),
+            Self::Evicted => Self::Evicted,
+            Self::Removed => Self::Removed,
         }
     }
 }
@@ -37,15 +46,90 @@ impl<Q: Query> QueryOutput<Q> {
             Self::Calculating(fork, rev, _lock) => {
                 panic!("Still calculating by {:?} {:?}", fork, rev)
             }
+            Self::Evicted => panic!("Output evicted"),
+            Self::Removed => panic!("Query removed"),
         }
     }
+}
 
-    pub fn unwrap_ref(&self) -> &Arc<Q::Output> {
-        match self {
-            Self::Calculated(out) => out,
-            Self::Calculating(fork, rev, _lock) => {
-                panic!("Still calculating by {:?} {:?}", fork, rev)
-            }
+/// Revisions of a cell.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Stamps {
+    /// Latest revision of the dependencies the output was calculated or validated with.
+    pub rev: Revision,
+    /// Revision the output last changed at.
+    pub changed_at: Revision,
+    /// Revision the output was last calculated or validated at.
+    pub verified_at: Revision,
+}
+
+impl Stamps {
+    pub fn new(rev: Revision) -> Self {
+        Self {
+            rev,
+            changed_at: rev,
+            verified_at: rev,
+        }
+    }
+}
+
+/// Cycle heads a cell was calculated with.
+#[derive(Clone, Debug)]
+pub(crate) struct CycleHeads {
+    /// Heads of the cycles the cell is part of, they are calculated again to validate it.
+    pub heads: Vec<DepIdx>,
+    /// Heads that haven't converged yet, the output is provisional until they do.
+    pub pending: Vec<DepIdx>,
+}
+
+impl CycleHeads {
+    /// Heads of a cell calculated out of provisional outputs, if there were any.
+    pub fn provisional(pending: Vec<DepIdx>) -> Option<Arc<Self>> {
+        if pending.is_empty() {
+            return None;
+        }
+        Some(Arc::new(Self {
+            heads: pending.clone(),
+            pending,
+        }))
+    }
+}
+
+/// Type erased view of a cell, used to validate it as a dependency.
+pub(crate) struct DepCell {
+    pub stamps: Stamps,
+    pub durability: Durability,
+    pub deps: Arc<Vec<Dep>>,
+    pub removed: bool,
+    pub on_cycle: bool,
+    pub provisional: bool,
+    /// Reservation of another fork calculating the cell right now.
+    pub lock: Option<(ForkId, ReservationReader)>,
+}
+
+impl DepCell {
+    /// Cell calculated on a cycle or abandoned, it changes right now.
+    pub fn changed_now(durability: Durability, current_rev: Revision) -> Self {
+        Self {
+            stamps: Stamps::new(current_rev),
+            durability,
+            deps: Default::default(),
+            removed: false,
+            on_cycle: false,
+            provisional: false,
+            lock: None,
+        }
+    }
+
+    /// How the cell changed for a dependent verified at `verified_at`, which read it as `dep`.
+    pub fn invalidation(&self, dep: &Dep, verified_at: Revision) -> Invalidation {
+        if self.stamps.changed_at > verified_at {
+            Invalidation::Outdated(self.stamps.changed_at, dep.idx)
+        } else if self.stamps.rev > dep.query_rev {
+            Invalidation::Revisioned(self.stamps.rev, dep.idx)
+        } else {
+            Invalidation::Fresh
         }
     }
 }
@@ -54,7 +138,11 @@ pub(crate) struct QueryCell<Q: Query> {
     output: QueryOutput<Q>, // Arc<Q::Output>,
     idx: usize,
     rev: Revision,
-    deps: Vec<Dep>,
+    changed_at: Revision,
+    verified_at: Revision,
+    durability: Durability,
+    deps: Arc<Vec<Dep>>,
+    cycle: Option<Arc<CycleHeads>>,
 }
 
 impl<Q: Query> fmt::Debug for QueryCell<Q> {
@@ -64,18 +152,33 @@ impl<Q: Query> fmt::Debug for QueryCell<Q> {
             .field("output", &self.output)
             .field("idx", &self.idx)
             .field("rev", &self.rev)
+            .field("changed_at", &self.changed_at)
+            .field("verified_at", &self.verified_at)
+            .field("durability", &self.durability)
             .field("deps", &self.deps)
+            .field("cycle", &self.cycle)
             .finish()
     }
 }
 
 impl<Q: Query> QueryCell<Q> {
-    fn calculated(output: Arc<Q::Output>, rev: Revision, idx: usize, deps: Vec<Dep>) -> Self {
+    fn calculated(
+        output: Arc<Q::Output>,
+        stamps: Stamps,
+        durability: Durability,
+        idx: usize,
+        deps: Vec<Dep>,
+        cycle: Option<Arc<CycleHeads>>,
+    ) -> Self {
         Self {
             output: QueryOutput::Calculated(output),
-            rev,
+            rev: stamps.rev,
+            changed_at: stamps.changed_at,
+            verified_at: stamps.verified_at,
+            durability,
             idx,
-            deps,
+            deps: Arc::new(deps),
+            cycle,
         }
     }
 
@@ -83,8 +186,25 @@ impl<Q: Query> QueryCell<Q> {
         Self {
             output: QueryOutput::Calculating(fork, rev, lock),
             rev,
+            changed_at: Default::default(),
+            verified_at: Default::default(),
+            durability: Default::default(),
             idx,
             deps: Default::default(),
+            cycle: None,
+        }
+    }
+
+    /// Output of a cycle head read while the head is calculated.
+    pub fn provisional(&self, output: Arc<Q::Output>) -> Self {
+        let head = self.as_dep().idx;
+        Self {
+            output: QueryOutput::Calculated(output),
+            cycle: Some(Arc::new(CycleHeads {
+                heads: vec![head],
+                pending: vec![head],
+            })),
+            ..self.clone()
         }
     }
 
@@ -96,28 +216,88 @@ impl<Q: Query> QueryCell<Q> {
         self.rev
     }
 
+    pub fn stamps(&self) -> Stamps {
+        Stamps {
+            rev: self.rev,
+            changed_at: self.changed_at,
+            verified_at: self.verified_at,
+        }
+    }
+
+    fn set_stamps(&mut self, stamps: Stamps) {
+        self.rev = stamps.rev;
+        self.changed_at = stamps.changed_at;
+        self.verified_at = stamps.verified_at;
+    }
+
     pub fn idx(&self) -> usize {
         self.idx
     }
 
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
     pub fn as_dep(&self) -> Dep {
         Dep {
-            idx: DepIdx {
-                query_name: std::any::type_name::<Q>(),
-                query_type: TypeId::of::<Q>(),
-                query_idx: self.idx,
-            },
+            idx: DepIdx::of::<Q>(self.idx),
             query_rev: self.rev,
-            query_deps: self.deps.clone(),
+            durability: self.durability,
         }
     }
 
-    pub fn deps(&self) -> &[Dep] {
+    pub fn deps(&self) -> &Arc<Vec<Dep>> {
         &self.deps
     }
 
-    pub fn on_cycle(&mut self, query: &Q) {
-        let o = query.on_cycle();
+    /// Heads of the cycles the cell is part of.
+    pub fn heads(&self) -> &[DepIdx] {
+        self.cycle.as_ref().map_or(&[], |cycle| &cycle.heads)
+    }
+
+    /// Heads that have to converge before the output is final.
+    pub fn pending(&self) -> &[DepIdx] {
+        self.cycle.as_ref().map_or(&[], |cycle| &cycle.pending)
+    }
+
+    pub fn is_provisional(&self) -> bool {
+        !self.pending().is_empty()
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        matches!(self.output, QueryOutput::Evicted)
+    }
+
+    pub fn is_removed(&self) -> bool {
+        matches!(self.output, QueryOutput::Removed)
+    }
+
+    /// Output that is still valid, nothing it depends on changed since it was verified.
+    /// `changed` is the last revision of a change at least as durable as the cell.
+    pub fn verified_output(
+        &self,
+        changed: impl Fn(Durability) -> Revision,
+    ) -> Option<&Arc<Q::Output>> {
+        match &self.output {
+            QueryOutput::Calculated(output)
+                if !self.is_provisional()
+                    && (self.deps.is_empty() || changed(self.durability) <= self.verified_at) =>
+            {
+                Some(output)
+            }
+            _ => None,
+        }
+    }
+
+    fn remove(&mut self, rev: Revision) {
+        self.output = QueryOutput::Removed;
+        self.set_stamps(Stamps::new(rev));
+        self.deps = Default::default();
+        self.cycle = None;
+    }
+
+    pub fn on_cycle(&mut self, query: &Q, cycle: &Cycle) {
+        let o = query.on_cycle(cycle);
         self.output = QueryOutput::Calculated(Arc::new(o));
     }
 
@@ -136,38 +316,25 @@ impl<Q: Query> QueryCell<Q> {
                     current_fork,
                     current_rev
                 );
+                if lock.is_ready() && *rev == current_rev {
+                    return CycleDetection::Abandoned;
+                }
                 if *fork == current_fork && *rev == current_rev {
                     return CycleDetection::CycleDetected;
                 }
                 else if *rev != current_rev {
                     return CycleDetection::Canceled;
                 }
-                CycleDetection::Locked(lock.clone())
+                CycleDetection::Locked(*fork, lock.clone())
             }
             _ => CycleDetection::Ok,
         }
     }
 
-    fn update_rev(&mut self, caused_by: DepIdx, rev: Revision) {
+    fn update_rev(&mut self, rev: Revision, deps: Vec<Dep>) {
         self.rev = rev;
-
-        fn rec(dep: &mut Dep, caused_by: DepIdx, rev: Revision) -> bool {
-            let changed_children = dep
-                .query_deps
-                .iter_mut()
-                .any(|dep| rec(dep, caused_by, rev));
-
-            if changed_children || dep.idx == caused_by {
-                dep.query_rev = rev;
-                true
-            } else {
-                false
-            }
-        }
-
-        self.deps.iter_mut().for_each(|d| {
-            rec(d, caused_by, rev);
-        });
+        self.durability = deps.durability();
+        self.deps = Arc::new(deps);
     }
 }
 
@@ -176,140 +343,349 @@ impl<Q: Query> Clone for QueryCell<Q> {
         Self {
             output: self.output.clone(),
             rev: self.rev,
+            changed_at: self.changed_at,
+            verified_at: self.verified_at,
+            durability: self.durability,
             idx: self.idx,
             deps: self.deps.clone(),
+            cycle: self.cycle.clone(),
         }
     }
 }
 
-#[async_trait]
 pub(crate) trait Storage: Any + Send + Sync {
-    fn dyn_query(&self, dep: &Dep) -> Box<dyn DynQuery>;
+    fn dyn_query(&self, idx: &DepIdx) -> Option<Box<dyn DynQuery>>;
+    fn dep_cell(&self, dep: &Dep, fork: ForkId, current_rev: Revision) -> DepCell;
     fn update_output_dyn(
-        &mut self,
+        &self,
         dep: &Dep,
-        caused_by: DepIdx,
         dyn_output: Box<dyn Any + Send + Sync>,
+        tracked: Tracked,
+        current_rev: Revision,
+    ) -> DepCell;
+    fn update_dep_rev(
+        &self,
+        dep: &Dep,
         rev: Revision,
-    ) -> Invalidation;
-    fn update_dep_rev(&mut self, dep: &Dep, caused_by: DepIdx, rev: Revision);
-    fn update_rev(&mut self, idx: usize, caused_by: DepIdx, rev: Revision);
+        deps: Vec<Dep>,
+        current_rev: Revision,
+    ) -> DepCell;
+    fn verify_dep(&self, dep: &Dep, current_rev: Revision) -> DepCell;
     fn dep_rev(&self, dep: &Dep) -> Revision;
-    fn as_any(&self) -> &(dyn Any + Send + Sync);
-    fn as_any_mut(&mut self) -> &mut (dyn Any + Send + Sync);
+    fn is_constant(&self, dep: &Dep) -> bool;
+    fn deps(&self, dep: &Dep) -> Arc<Vec<Dep>>;
+    fn dependents(&self, removed: &HashSet<(TypeId, usize)>) -> Vec<(usize, Arc<Vec<Dep>>)>;
+    fn release(&self, idx: usize, rev: Revision) -> DepIdx;
+    fn finalize(&self, dep: &Dep, head: &DepIdx) -> Option<Arc<Vec<Dep>>>;
+    fn lock_read(&self) -> Box<dyn StorageRead + '_>;
+    #[cfg(feature = "serde")]
+    fn is_interned(&self) -> bool;
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+/// Storage locked for reading, other storages can be locked along to copy all of them at once.
+pub(crate) trait StorageRead {
+    /// Storage with the same cells, the first write to either of them copies the cells.
+    fn share(&self) -> Arc<dyn Storage>;
+    fn is_shared(&self) -> bool;
 }
 
 struct DynQueryWrapper<Q: Query> {
     query: Arc<Q>,
+    idx: usize,
 }
 #[async_trait]
 impl<Q: Query> DynQuery for DynQueryWrapper<Q> {
-    async fn calc(&self, system: &Runtime) -> Box<dyn Any + Send + Sync> {
-        let out = self.query.calc(system).await;
-        Box::new(out)
+    async fn calc(
+        &self,
+        system: &Runtime,
+    ) -> Result<(Box<dyn Any + Send + Sync>, Tracked), Cancelled> {
+        let tracker = QueryTracker::new(system, self.idx, &self.query);
+        let out = tracker.calc(&*self.query).await?;
+        Ok((Box::new(out), tracker.into_tracked().await))
+    }
+
+    async fn refresh(&self, system: &Runtime) {
+        system.refresh_query(self.query.clone()).await;
     }
 }
 
 pub(crate) struct QueryStorage<Q: Query> {
     queries: HashMap<Arc<Q>, usize>,
+    keys: Vec<Option<Arc<Q>>>,
     cells: Vec<QueryCell<Q>>,
+    lru: Lru,
+    /// Provisional outputs of cycle heads being calculated, and whether they were read.
+    iterations: HashMap<usize, (Arc<Q::Output>, bool)>,
+}
+
+impl<Q: Query> Clone for QueryStorage<Q> {
+    fn clone(&self) -> Self {
+        Self {
+            queries: self.queries.clone(),
+            keys: self.keys.clone(),
+            cells: self.cells.clone(),
+            lru: self.lru.clone(),
+            iterations: self.iterations.clone(),
+        }
+    }
 }
 
 impl<Q: Query> Default for QueryStorage<Q> {
     fn default() -> Self {
         Self {
             queries: Default::default(),
+            keys: Default::default(),
             cells: Default::default(),
+            lru: Lru::new(Q::LRU_CAPACITY),
+            iterations: Default::default(),
         }
     }
 }
 
-#[async_trait]
-impl<Q: Query> Storage for QueryStorage<Q> {
+impl<Q: Query> QueryStorage<Q> {
     #[tracing::instrument(skip(self))]
-    fn dyn_query(&self, dep: &Dep) -> Box<dyn DynQuery> {
-        let idx = dep.idx.query_idx;
-        let query = self
-            .queries
-            .iter()
-            .find_map(|(key, &val)| if val == idx { Some(key) } else { None })
-            .unwrap()
-            .clone();
-        Box::new(DynQueryWrapper { query })
+    pub fn dyn_query(&self, idx: &DepIdx) -> Option<Box<dyn DynQuery>> {
+        let query = self.keys[idx.query_idx].clone()?;
+        Some(Box::new(DynQueryWrapper {
+            query,
+            idx: idx.query_idx,
+        }))
     }
 
+    /// Dependencies haven't changed their outputs, only their revisions.
     #[tracing::instrument(skip(self))]
-    fn update_rev(&mut self, idx: usize, caused_by: DepIdx, rev: Revision) {
+    pub fn update_rev(&mut self, idx: usize, rev: Revision, deps: Vec<Dep>) {
         let cell = &mut self.cells[idx];
+        if cell.is_removed() {
+            return;
+        }
         tracing::debug!("From: {:?}", &cell);
 
-        cell.update_rev(caused_by, rev);
+        cell.update_rev(rev, deps);
 
         tracing::debug!("To: {:?}", &cell);
     }
 
     #[tracing::instrument(skip(self))]
-    fn update_dep_rev(&mut self, dep: &Dep, caused_by: DepIdx, rev: Revision) {
+    pub fn update_dep_rev(
+        &mut self,
+        dep: &Dep,
+        rev: Revision,
+        deps: Vec<Dep>,
+        current_rev: Revision,
+    ) -> DepCell {
         let idx = dep.idx.query_idx;
 
-        self.update_rev(idx, caused_by, rev)
+        self.update_rev(idx, rev, deps);
+        self.verify_dep(dep, current_rev)
     }
 
+    /// Output recalculated during validation, it changes only when it's different from the old one.
     #[tracing::instrument(skip(self, dyn_output))]
-    fn update_output_dyn(
+    pub fn update_output_dyn(
         &mut self,
         dep: &Dep,
-        caused_by: DepIdx,
         dyn_output: Box<dyn Any + Send + Sync>,
-        rev: Revision,
-    ) -> Invalidation {
+        tracked: Tracked,
+        current_rev: Revision,
+    ) -> DepCell {
         let idx = dep.idx.query_idx;
         let dyn_output: Box<dyn Any> = dyn_output;
         let output = dyn_output.downcast::<Q::Output>().unwrap();
         let cell = &mut self.cells[idx];
         tracing::debug!("From: {:?}", &cell);
 
-        cell.update_rev(caused_by, rev);
+        let rev = tracked.deps.last_rev().unwrap_or(current_rev);
+        cell.update_rev(rev, tracked.deps);
+        cell.verified_at = current_rev;
+        cell.cycle = CycleHeads::provisional(tracked.pending);
+
+        match &cell.output {
+            QueryOutput::Calculated(old) if **old == *output => {
+                tracing::debug!("Output is same");
+            }
+            QueryOutput::Calculated(_) => {
+                tracing::debug!("Output is different. Outdated!");
+                cell.output = QueryOutput::Calculated(Arc::from(output));
+                cell.changed_at = current_rev;
+            }
+            _ => {
+                tracing::debug!("Output was evicted, can't compare. Outdated!");
+                cell.output = QueryOutput::Calculated(Arc::from(output));
+                cell.changed_at = current_rev;
+                self.lru.touch(idx);
+                self.lru.calculated(idx);
+                self.evict_lru(idx);
+            }
+        }
 
-        if **cell.output.unwrap_ref() != *output {
-            cell.output = QueryOutput::Calculated(Arc::from(output));
+        tracing::debug!("Into: {:?}", &self.cells[idx]);
+        self.dep_cell_at(idx, None)
+    }
 
-            tracing::debug!("Into: {:?}", &cell);
-            tracing::debug!("Output is different. Outdated!");
+    pub fn verify_dep(&mut self, dep: &Dep, current_rev: Revision) -> DepCell {
+        let idx = dep.idx.query_idx;
+        self.verify(idx, current_rev);
+        self.dep_cell_at(idx, None)
+    }
 
-            Invalidation::Outdated(rev, caused_by)
-        } else {
-            tracing::debug!("Into: {:?}", &cell);
-            tracing::debug!("Output is same");
-            Invalidation::Revisioned(rev, caused_by)
+    pub fn dep_cell(&self, dep: &Dep, fork: ForkId, current_rev: Revision) -> DepCell {
+        let idx = dep.idx.query_idx;
+        let lock = match self.cells[idx].detect_cycle_or_lock(fork, current_rev) {
+            CycleDetection::Locked(fork, lock) => Some((fork, lock)),
+            // Calculated by this very fork, it's on a cycle and changes right now.
+            // Abandoned one is calculated again by whoever reads it.
+            CycleDetection::CycleDetected | CycleDetection::Abandoned => {
+                return DepCell::changed_now(self.cells[idx].durability, current_rev);
+            }
+            _ => None,
+        };
+        self.dep_cell_at(idx, lock)
+    }
+
+    fn dep_cell_at(&self, idx: usize, lock: Option<(ForkId, ReservationReader)>) -> DepCell {
+        let cell = &self.cells[idx];
+        DepCell {
+            stamps: cell.stamps(),
+            durability: cell.durability,
+            deps: cell.deps.clone(),
+            removed: cell.is_removed(),
+            on_cycle: !cell.heads().is_empty(),
+            provisional: cell.is_provisional(),
+            lock,
         }
     }
 
-    fn dep_rev(&self, dep: &Dep) -> Revision {
+    pub fn dep_rev(&self, dep: &Dep) -> Revision {
         let idx = dep.idx.query_idx;
         self.cells[idx].rev
     }
 
-    fn as_any(&self) -> &(dyn Any + Send + Sync) {
-        self
+    /// Value that never changes: an interned one, or a query calculated without dependencies.
+    pub fn is_constant(&self, dep: &Dep) -> bool {
+        let cell = &self.cells[dep.idx.query_idx];
+        !cell.is_removed() && (Q::INTERNED || (!Q::INPUT && cell.deps.is_empty()))
     }
 
-    fn as_any_mut(&mut self) -> &mut (dyn Any + Send + Sync) {
-        self
+    /// Cells depending on any of the removed queries.
+    pub fn dependents(&self, removed: &HashSet<(TypeId, usize)>) -> Vec<(usize, Arc<Vec<Dep>>)> {
+        let is_removed = |dep: &Dep| removed.contains(&(dep.query_type(), dep.idx.query_idx));
+
+        self.cells
+            .iter()
+            .filter(|cell| !cell.is_removed() && cell.deps.iter().any(is_removed))
+            .map(|cell| (cell.idx, cell.deps.clone()))
+            .collect()
     }
 }
 
 impl<Q: Query> QueryStorage<Q> {
+    pub fn idx(&self, query: &Q) -> Option<usize> {
+        self.queries.get(query).copied()
+    }
+
     pub fn get(&self, query: &Q) -> &QueryCell<Q> {
         let idx = self.queries[query];
+        self.lru.touch(idx);
         &self.cells[idx]
     }
 
-    pub fn contains_query(&self, query: &Q) -> bool {
-        self.queries.contains_key(query)
+    pub fn try_get(&self, query: &Q) -> Option<&QueryCell<Q>> {
+        let idx = *self.queries.get(query)?;
+        self.lru.touch(idx);
+        Some(&self.cells[idx])
+    }
+
+    /// Returns the output when it's still valid, without touching anything but the LRU.
+    pub fn try_verified(
+        &self,
+        query: &Q,
+        changed: impl Fn(Durability) -> Revision,
+    ) -> Option<QueryCell<Q>> {
+        let idx = *self.queries.get(query)?;
+        let cell = &self.cells[idx];
+        cell.verified_output(changed)?;
+        self.lru.touch(idx);
+        Some(cell.clone())
+    }
+
+    /// Dependencies of the cell were validated at `rev`.
+    pub fn verify(&mut self, idx: usize, rev: Revision) {
+        let cell = &mut self.cells[idx];
+        cell.verified_at = cell.verified_at.max(rev);
     }
 
-    pub async fn reserve(
+    pub fn get_by_idx(&self, idx: usize) -> (&Arc<Q>, &QueryCell<Q>) {
+        let key = self.keys[idx].as_ref().expect("Query removed");
+        (key, &self.cells[idx])
+    }
+
+    /// Whether setting the input to `output` would change anything.
+    pub fn input_changed(&self, query: &Q, output: &Q::Output, durability: Durability) -> bool
+    where
+        Q::Output: Eq,
+    {
+        let cell = match self.queries.get(query) {
+            Some(&idx) => &self.cells[idx],
+            None => return true,
+        };
+        match &cell.output {
+            QueryOutput::Calculated(old) => **old != *output || cell.durability != durability,
+            _ => true,
+        }
+    }
+
+    /// Head at `idx` is calculated with `output` in this iteration of its cycle.
+    pub fn start_iteration(&mut self, idx: usize, output: Arc<Q::Output>) {
+        self.iterations.insert(idx, (output, false));
+    }
+
+    /// Provisional output of the head at `idx`, if it's being iterated.
+    pub fn cycle_output(&mut self, idx: usize) -> Option<Arc<Q::Output>> {
+        let (output, read) = self.iterations.get_mut(&idx)?;
+        *read = true;
+        Some(output.clone())
+    }
+
+    /// Returns whether the provisional output was read in the iteration.
+    pub fn end_iteration(&mut self, idx: usize) -> bool {
+        matches!(self.iterations.remove(&idx), Some((_, true)))
+    }
+
+    /// Cycle of `head` converged, returns dependencies of the cell if it was waiting for it.
+    pub fn finalize(&mut self, dep: &Dep, head: &DepIdx) -> Option<Arc<Vec<Dep>>> {
+        let cell = &mut self.cells[dep.idx.query_idx];
+        let cycle = cell.cycle.as_mut()?;
+        if !cycle.pending.contains(head) {
+            return None;
+        }
+        Arc::make_mut(cycle).pending.retain(|pending| pending != head);
+        Some(cell.deps.clone())
+    }
+
+    pub fn durability(&self, query: &Q) -> Option<Durability> {
+        self.queries.get(query).map(|&idx| self.cells[idx].durability)
+    }
+
+    /// Removes the query, dependents will notice the cell has changed in `rev`.
+    pub fn remove(&mut self, query: &Q, rev: Revision) -> Option<DepIdx> {
+        let idx = *self.queries.get(query)?;
+        Some(self.release(idx, rev))
+    }
+
+    pub fn release(&mut self, idx: usize, rev: Revision) -> DepIdx {
+        tracing::debug!("Release: {:?}", &self.cells[idx]);
+        if let Some(key) = self.keys[idx].take() {
+            self.queries.remove(&key);
+        }
+        self.lru.discarded(idx);
+        let cell = &mut self.cells[idx];
+        cell.remove(rev);
+        cell.as_dep().idx
+    }
+
+    pub fn reserve(
         &mut self,
         query: Arc<Q>,
         fork: ForkId,
@@ -317,7 +693,7 @@ impl<Q: Query> QueryStorage<Q> {
     ) -> Reservation {
         let idx = self.queries.get(&query).copied();
 
-        let (reservation, lock) = Reservation::new().await;
+        let (reservation, lock) = Reservation::new();
 
         match idx {
             None => {
@@ -325,24 +701,48 @@ impl<Q: Query> QueryStorage<Q> {
 
                 let cell = QueryCell::calculating(fork, current_rev, idx, lock);
                 self.cells.push(cell);
+                self.keys.push(Some(query.clone()));
                 self.queries.insert(query, idx);
+                self.lru.push();
             }
             Some(idx) => {
                 let cell = &mut self.cells[idx];
                 cell.output = QueryOutput::Calculating(fork, current_rev, lock);
                 cell.rev = current_rev;
+                self.lru.discarded(idx);
             }
         }
 
         reservation
     }
 
+    /// Reserves the cell again if its calculation was abandoned,
+    /// `None` if another reader took it over already.
+    pub fn take_over(
+        &mut self,
+        idx: usize,
+        fork: ForkId,
+        current_rev: Revision,
+    ) -> Option<Reservation> {
+        let cell = &mut self.cells[idx];
+        match &cell.output {
+            QueryOutput::Calculating(_, _, lock) if lock.is_ready() => {
+                let (reservation, lock) = Reservation::new();
+                cell.output = QueryOutput::Calculating(fork, current_rev, lock);
+                Some(reservation)
+            }
+            _ => None,
+        }
+    }
+
     pub fn insert_calculated(
         &mut self,
         query: Arc<Q>,
         output: Q::Output,
-        rev: Revision,
+        stamps: Stamps,
+        durability: Durability,
         deps: Vec<Dep>,
+        cycle: Option<Arc<CycleHeads>>,
     ) -> QueryCell<Q> {
         let idx = self.queries.get(&query).copied();
 
@@ -350,19 +750,310 @@ impl<Q: Query> QueryStorage<Q> {
             None => {
                 let idx = self.cells.len();
 
-                let cell = QueryCell::calculated(Arc::new(output), rev, idx, deps);
+                let output = Arc::new(output);
+                let cell = QueryCell::calculated(output, stamps, durability, idx, deps, cycle);
                 self.cells.push(cell.clone());
+                self.keys.push(Some(query.clone()));
                 self.queries.insert(query, idx);
+                self.lru.push();
+                self.lru.calculated(idx);
+                self.evict_lru(idx);
                 cell
             }
             Some(idx) => {
                 let cell = &mut self.cells[idx];
                 cell.output = QueryOutput::Calculated(Arc::new(output));
-                cell.rev = rev;
-                cell.deps = deps;
+                cell.set_stamps(stamps);
+                cell.durability = durability;
+                cell.deps = Arc::new(deps);
+                cell.cycle = cycle;
 
-                cell.clone()
+                let cell = cell.clone();
+                self.lru.touch(idx);
+                self.lru.calculated(idx);
+                self.evict_lru(idx);
+                cell
             }
         }
     }
+
+    /// Drops least recently used outputs over the capacity of `Q`.
+    /// Evicted cells keep their revision and dependencies, so they can still be validated.
+    fn evict_lru(&mut self, keep: usize) {
+        while self.lru.is_over_capacity() {
+            let idx = match self.lru.evict(keep) {
+                Some(idx) => idx,
+                None => break,
+            };
+
+            tracing::debug!("Evict: {:?}", &self.cells[idx]);
+            self.cells[idx].output = QueryOutput::Evicted;
+        }
+    }
+}
+
+/// Every query type has its own lock, so queries of different types never wait for each other.
+/// Cells can be shared with snapshots, they are copied when they're written.
+pub(crate) struct LockedStorage<Q: Query>(RwLock<Arc<QueryStorage<Q>>>);
+
+pub(crate) struct StorageWriteGuard<'a, Q: Query>(RwLockWriteGuard<'a, Arc<QueryStorage<Q>>>);
+
+impl<Q: Query> Deref for StorageWriteGuard<'_, Q> {
+    type Target = QueryStorage<Q>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<Q: Query> DerefMut for StorageWriteGuard<'_, Q> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        Arc::get_mut(&mut self.0).expect("Storage copied before writing")
+    }
+}
+
+impl<Q: Query> Default for LockedStorage<Q> {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<Q: Query> LockedStorage<Q> {
+    pub fn new(storage: QueryStorage<Q>) -> Self {
+        Self(RwLock::new(Arc::new(storage)))
+    }
+
+    /// Storage is never left in a broken state by a panic, so poisoning is ignored.
+    pub fn read(&self) -> RwLockReadGuard<'_, Arc<QueryStorage<Q>>> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Copies the cells first if they are shared.
+    pub fn write(&self) -> StorageWriteGuard<'_, Q> {
+        let mut storage = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if Arc::get_mut(&mut storage).is_none() {
+            Self::unshare(&mut storage);
+        }
+        StorageWriteGuard(storage)
+    }
+
+    // Kept out of line, the copy would take stack space of every query calculation otherwise.
+    #[inline(never)]
+    fn unshare(storage: &mut Arc<QueryStorage<Q>>) {
+        tracing::debug!("Copying storage of {}", std::any::type_name::<Q>());
+        *storage = Arc::new((**storage).clone());
+    }
+}
+
+impl<Q: Query> Storage for LockedStorage<Q> {
+    fn dyn_query(&self, idx: &DepIdx) -> Option<Box<dyn DynQuery>> {
+        self.read().dyn_query(idx)
+    }
+
+    fn dep_cell(&self, dep: &Dep, fork: ForkId, current_rev: Revision) -> DepCell {
+        self.read().dep_cell(dep, fork, current_rev)
+    }
+
+    fn update_output_dyn(
+        &self,
+        dep: &Dep,
+        dyn_output: Box<dyn Any + Send + Sync>,
+        tracked: Tracked,
+        current_rev: Revision,
+    ) -> DepCell {
+        self.write()
+            .update_output_dyn(dep, dyn_output, tracked, current_rev)
+    }
+
+    fn update_dep_rev(
+        &self,
+        dep: &Dep,
+        rev: Revision,
+        deps: Vec<Dep>,
+        current_rev: Revision,
+    ) -> DepCell {
+        self.write().update_dep_rev(dep, rev, deps, current_rev)
+    }
+
+    fn verify_dep(&self, dep: &Dep, current_rev: Revision) -> DepCell {
+        self.write().verify_dep(dep, current_rev)
+    }
+
+    fn dep_rev(&self, dep: &Dep) -> Revision {
+        self.read().dep_rev(dep)
+    }
+
+    fn is_constant(&self, dep: &Dep) -> bool {
+        self.read().is_constant(dep)
+    }
+
+    fn deps(&self, dep: &Dep) -> Arc<Vec<Dep>> {
+        self.read().cells[dep.idx.query_idx].deps.clone()
+    }
+
+    fn dependents(&self, removed: &HashSet<(TypeId, usize)>) -> Vec<(usize, Arc<Vec<Dep>>)> {
+        self.read().dependents(removed)
+    }
+
+    fn release(&self, idx: usize, rev: Revision) -> DepIdx {
+        self.write().release(idx, rev)
+    }
+
+    fn finalize(&self, dep: &Dep, head: &DepIdx) -> Option<Arc<Vec<Dep>>> {
+        self.write().finalize(dep, head)
+    }
+
+    fn lock_read(&self) -> Box<dyn StorageRead + '_> {
+        Box::new(self.read())
+    }
+
+    #[cfg(feature = "serde")]
+    fn is_interned(&self) -> bool {
+        Q::INTERNED
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl<Q: Query> StorageRead for RwLockReadGuard<'_, Arc<QueryStorage<Q>>> {
+    fn share(&self) -> Arc<dyn Storage> {
+        Arc::new(LockedStorage(RwLock::new(Arc::clone(self))))
+    }
+
+    fn is_shared(&self) -> bool {
+        Arc::strong_count(self) > 1
+    }
+}
+
+#[cfg(feature = "serde")]
+mod persist {
+    use super::{CycleHeads, QueryCell, QueryOutput, QueryStorage};
+    use crate::runtime::lru::Lru;
+    use crate::runtime::persist::{CellData, DepData, Dropped, HeadData, Persist};
+    use crate::Query;
+    use std::any::TypeId;
+    use std::io;
+    use std::sync::Arc;
+
+    impl<Q: Query> QueryStorage<Q> {
+        /// Cells depending on queries that are not restored, so they can't be validated.
+        /// Cycles are validated through their heads, so they're needed as well.
+        pub(crate) fn unsaved(&self, persist: &Persist, dropped: &Dropped) -> Vec<usize> {
+            let query_type = TypeId::of::<Q>();
+            self.cells
+                .iter()
+                .filter(|cell| !cell.is_removed() && !dropped.contains(&(query_type, cell.idx)))
+                .filter(|cell| {
+                    let deps = cell.deps.iter().map(|dep| &dep.idx);
+                    let mut deps = deps.chain(cell.heads());
+                    deps.any(|dep| !persist.is_restored(dep, dropped))
+                })
+                .map(|cell| cell.idx)
+                .collect()
+        }
+
+        /// Dropped cells are saved as removed ones, they're calculated again under new indexes.
+        pub(crate) fn save_data(&self, dropped: &Dropped) -> Vec<CellData<Option<&Q>, &Q::Output>> {
+            let query_type = TypeId::of::<Q>();
+            self.keys
+                .iter()
+                .zip(self.cells.iter())
+                .map(|(key, cell)| {
+                    if dropped.contains(&(query_type, cell.idx)) {
+                        return CellData {
+                            key: None,
+                            output: None,
+                            stamps: cell.stamps(),
+                            durability: cell.durability,
+                            deps: vec![],
+                            heads: vec![],
+                        };
+                    }
+
+                    let output = match &cell.output {
+                        QueryOutput::Calculated(output) if !cell.is_provisional() => {
+                            Some(output.as_ref())
+                        }
+                        _ => None,
+                    };
+
+                    CellData {
+                        key: key.as_deref(),
+                        output,
+                        stamps: cell.stamps(),
+                        durability: cell.durability,
+                        deps: cell.deps.iter().map(DepData::save).collect(),
+                        heads: cell.heads().iter().map(HeadData::save).collect(),
+                    }
+                })
+                .collect()
+        }
+
+        pub(crate) fn load_data(
+            data: Vec<CellData<Option<Q>, Q::Output>>,
+            persist: &Persist,
+        ) -> io::Result<Self> {
+            let mut storage = Self {
+                queries: Default::default(),
+                keys: Default::default(),
+                cells: Default::default(),
+                lru: Lru::new(Q::LRU_CAPACITY),
+                iterations: Default::default(),
+            };
+
+            for (idx, cell) in data.into_iter().enumerate() {
+                let deps = cell
+                    .deps
+                    .into_iter()
+                    .map(|dep| dep.load(persist))
+                    .collect::<io::Result<_>>()?;
+                let heads = cell
+                    .heads
+                    .into_iter()
+                    .map(|head| head.load(persist))
+                    .collect::<io::Result<Vec<_>>>()?;
+                let cycle = if heads.is_empty() {
+                    None
+                } else {
+                    Some(Arc::new(CycleHeads {
+                        heads,
+                        pending: vec![],
+                    }))
+                };
+                let output = match cell.output {
+                    Some(output) => QueryOutput::Calculated(Arc::new(output)),
+                    None => QueryOutput::Evicted,
+                };
+
+                let key = cell.key.map(Arc::new);
+                let output = match key {
+                    Some(_) => output,
+                    None => QueryOutput::Removed,
+                };
+                let calculated = matches!(output, QueryOutput::Calculated(_));
+                storage.cells.push(QueryCell {
+                    output,
+                    idx,
+                    rev: cell.stamps.rev,
+                    changed_at: cell.stamps.changed_at,
+                    verified_at: cell.stamps.verified_at,
+                    durability: cell.durability,
+                    deps: Arc::new(deps),
+                    cycle,
+                });
+                if let Some(key) = &key {
+                    storage.queries.insert(key.clone(), idx);
+                }
+                storage.keys.push(key);
+                storage.lru.push();
+                if calculated {
+                    storage.lru.calculated(idx);
+                }
+            }
+
+            Ok(storage)
+        }
+    }
 }
@@ -0,0 +1,186 @@
+use super::storage::{LockedStorage, Storage};
+use crate::Query;
+use std::any::TypeId;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+const SHARDS: usize = 16;
+
+type Shard = RwLock<HashMap<TypeId, Arc<dyn Storage>>>;
+
+/// Storages of all query types, split into shards.
+/// Registering a new query type locks only its own shard, and only for the insert.
+///
+/// Shards are shared with snapshots until the next write, storages stay shared until they
+/// are written themselves. Interned values are never unshared, so snapshots and the runtime
+/// give out ids from the same storages.
+pub(crate) struct StorageMap {
+    shards: RwLock<Arc<Vec<Shard>>>,
+    interned: Arc<Shard>,
+}
+
+impl Default for StorageMap {
+    fn default() -> Self {
+        let shards = (0..SHARDS).map(|_| Default::default()).collect();
+        Self {
+            shards: RwLock::new(Arc::new(shards)),
+            interned: Default::default(),
+        }
+    }
+}
+
+impl StorageMap {
+    fn shards(&self) -> RwLockReadGuard<'_, Arc<Vec<Shard>>> {
+        self.shards.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn shard_idx(type_id: TypeId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        type_id.hash(&mut hasher);
+        hasher.finish() as usize % SHARDS
+    }
+
+    fn interned(&self) -> RwLockReadGuard<'_, HashMap<TypeId, Arc<dyn Storage>>> {
+        self.interned.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, type_id: TypeId) -> Option<Arc<dyn Storage>> {
+        if let Some(storage) = self.interned().get(&type_id) {
+            return Some(storage.clone());
+        }
+
+        let shards = self.shards();
+        let shard = shards[Self::shard_idx(type_id)]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        shard.get(&type_id).cloned()
+    }
+
+    pub fn typed<Q: Query>(&self) -> Option<Arc<LockedStorage<Q>>> {
+        let storage = self.get(TypeId::of::<Q>())?;
+        let storage = storage
+            .into_any()
+            .downcast::<LockedStorage<Q>>()
+            .unwrap_or_else(|_| panic!("Couldn't downcast to storage"));
+        Some(storage)
+    }
+
+    pub fn typed_or_default<Q: Query>(&self) -> Arc<LockedStorage<Q>> {
+        if let Some(storage) = self.typed::<Q>() {
+            return storage;
+        }
+
+        let type_id = TypeId::of::<Q>();
+        let shards = self.shards();
+        let shard = match Q::INTERNED {
+            true => &self.interned,
+            false => &shards[Self::shard_idx(type_id)],
+        };
+        let storage = shard
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(type_id)
+            .or_insert_with(|| Arc::new(LockedStorage::<Q>::default()))
+            .clone();
+        storage
+            .into_any()
+            .downcast::<LockedStorage<Q>>()
+            .unwrap_or_else(|_| panic!("Couldn't downcast to storage"))
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn insert(&self, type_id: TypeId, storage: Arc<dyn Storage>) {
+        let shards = self.shards();
+        let shard = match storage.is_interned() {
+            true => &self.interned,
+            false => &shards[Self::shard_idx(type_id)],
+        };
+        shard
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(type_id, storage);
+    }
+
+    pub fn all(&self) -> Vec<(TypeId, Arc<dyn Storage>)> {
+        let mut all = self.copied_on_write();
+        let interned = self.interned();
+        all.extend(interned.iter().map(|(&type_id, storage)| (type_id, storage.clone())));
+        all
+    }
+
+    /// Storages that are copied on write, all but the interned ones.
+    fn copied_on_write(&self) -> Vec<(TypeId, Arc<dyn Storage>)> {
+        self.shards()
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap_or_else(PoisonError::into_inner);
+                shard
+                    .iter()
+                    .map(|(&type_id, storage)| (type_id, storage.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Map sharing the current storages, it never sees writes done after [`StorageMap::unshare`].
+    /// Interned values are the exception, they're visible to every map sharing them.
+    pub fn pinned(&self) -> Self {
+        Self {
+            shards: RwLock::new(self.shards().clone()),
+            interned: self.interned.clone(),
+        }
+    }
+
+    /// Separates storages from pinned maps, so they can be written without affecting them.
+    /// Cells are shared until they are written, only written storages are copied.
+    pub fn unshare(&self) {
+        let mut shards = self.shards.write().unwrap_or_else(PoisonError::into_inner);
+        if Arc::strong_count(&shards) == 1 {
+            return;
+        }
+        tracing::debug!("Unsharing storages with snapshots");
+
+        // Every storage is locked at once, so the shared cells are consistent.
+        let locked = shards
+            .iter()
+            .map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner))
+            .collect::<Vec<_>>();
+        let storages = locked
+            .iter()
+            .map(|shard| {
+                shard
+                    .iter()
+                    .map(|(&type_id, storage)| (type_id, storage.lock_read()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let copy = storages
+            .iter()
+            .map(|shard| {
+                let shard = shard
+                    .iter()
+                    .map(|(type_id, storage)| (*type_id, storage.share()))
+                    .collect();
+                RwLock::new(shard)
+            })
+            .collect();
+
+        drop(storages);
+        drop(locked);
+        *shards = Arc::new(copy);
+    }
+
+    /// Storages with cells shared with pinned maps, not counting the interned ones.
+    pub fn shared(&self) -> usize {
+        if Arc::strong_count(&self.shards()) > 1 {
+            return self.copied_on_write().len();
+        }
+        self.copied_on_write()
+            .iter()
+            .filter(|(_, storage)| storage.lock_read().is_shared())
+            .count()
+    }
+}
@@ -0,0 +1,99 @@
+use super::{DepIdx, QueryStack};
+use crate::{Cycle, ForkId, ReservationReader};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Forks waiting for queries reserved by other forks.
+/// A fork that would wait, transitively, for itself is on a cycle instead.
+#[derive(Default)]
+pub(crate) struct WaitGraph {
+    waits: Mutex<Vec<Wait>>,
+    counter: AtomicUsize,
+}
+
+struct Wait {
+    id: usize,
+    waiter: ForkId,
+    holder: ForkId,
+    query: DepIdx,
+    /// Queries of the waiter, the innermost is the one waiting.
+    stack: QueryStack,
+    lock: ReservationReader,
+}
+
+/// Keeps the wait in the graph until it's dropped.
+pub(crate) struct WaitGuard<'a> {
+    graph: &'a WaitGraph,
+    id: usize,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        let mut waits = self.graph.waits.lock().expect("Wait graph lock");
+        waits.retain(|wait| wait.id != self.id);
+    }
+}
+
+impl WaitGraph {
+    /// Records `waiter`, with its `stack`, waiting for `query` reserved by `holder`.
+    /// Returns the cycle if `holder` is waiting for `waiter` already.
+    pub fn wait(
+        &self,
+        waiter: ForkId,
+        holder: ForkId,
+        query: DepIdx,
+        stack: &QueryStack,
+        lock: &ReservationReader,
+    ) -> Result<WaitGuard<'_>, Cycle> {
+        let mut waits = self.waits.lock().expect("Wait graph lock");
+        if let Some(path) = Self::path(&waits, holder, waiter) {
+            // Every fork on the path contributes its queries from the one the previous fork waits for.
+            let mut from = query;
+            let mut queries = vec![];
+            for wait in path {
+                queries.extend(waits[wait].stack.path(&from));
+                from = waits[wait].query;
+            }
+            queries.extend(stack.path(&from));
+            return Err(Cycle::new(queries));
+        }
+
+        let id = self.counter.fetch_add(1, Ordering::SeqCst);
+        waits.push(Wait {
+            id,
+            waiter,
+            holder,
+            query,
+            stack: stack.clone(),
+            lock: lock.clone(),
+        });
+        Ok(WaitGuard { graph: self, id })
+    }
+
+    /// Indices of the waits leading from `from` to `to`, skipping the ones that are over.
+    fn path(waits: &[Wait], from: ForkId, to: ForkId) -> Option<Vec<usize>> {
+        let mut path = vec![];
+        let mut visited = vec![from];
+        let mut stack = vec![(from, 0)];
+        while let Some((fork, start)) = stack.pop() {
+            path.truncate(stack.len());
+            if fork == to {
+                return Some(path);
+            }
+            let next = waits
+                .iter()
+                .enumerate()
+                .skip(start)
+                .find(|(_, wait)| wait.waiter == fork && !wait.lock.is_ready());
+            if let Some((idx, wait)) = next {
+                stack.push((fork, idx + 1));
+                if !visited.contains(&wait.holder) {
+                    visited.push(wait.holder);
+                    path.push(idx);
+                    stack.push((wait.holder, 0));
+                }
+            }
+        }
+        None
+    }
+}
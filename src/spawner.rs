@@ -0,0 +1,125 @@
+use crate::{Cancelled, Forked};
+use futures::channel::oneshot;
+use futures::future::{AbortHandle, Abortable, BoxFuture};
+use futures::task::{Context, Poll};
+use futures::{Future, FutureExt};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+/// Runs forks started with [`System::spawn`](crate::System::spawn) on an executor.
+pub trait Spawner: Send + Sync + 'static {
+    fn spawn(&self, fut: BoxFuture<'static, ()>);
+}
+
+/// Spawns on the tokio runtime the fork is started from.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioSpawner;
+
+#[cfg(feature = "tokio")]
+impl Spawner for TokioSpawner {
+    fn spawn(&self, fut: BoxFuture<'static, ()>) {
+        tokio::spawn(fut);
+    }
+}
+
+/// Spawns on the global smol executor.
+#[cfg(feature = "smol")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmolSpawner;
+
+#[cfg(feature = "smol")]
+impl Spawner for SmolSpawner {
+    fn spawn(&self, fut: BoxFuture<'static, ()>) {
+        smol::Task::spawn(fut).detach();
+    }
+}
+
+/// No [`Spawner`] is installed, see [`Runtime::set_spawner`](crate::Runtime::set_spawner).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoSpawner;
+
+impl fmt::Display for NoSpawner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No spawner installed with Runtime::set_spawner")
+    }
+}
+
+impl std::error::Error for NoSpawner {}
+
+/// Output of a spawned fork, or [`Cancelled`] if the fork was aborted.
+/// Dropping the handle aborts the fork.
+#[must_use = "dropping the handle aborts the fork"]
+pub struct JoinHandle<T> {
+    output: oneshot::Receiver<Result<T, Cancelled>>,
+    abort: AbortHandle,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Cancelled>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The sender is gone when the executor has dropped the task.
+        self.output
+            .poll_unpin(cx)
+            .map(|output| output.unwrap_or(Err(Cancelled)))
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+/// Spawner installed on the runtime, shared by its forks and snapshots.
+pub(crate) struct SpawnerSlot(Mutex<Option<Arc<dyn Spawner>>>);
+
+impl Default for SpawnerSlot {
+    fn default() -> Self {
+        Self(Mutex::new(default_spawner()))
+    }
+}
+
+/// Tokio wins if both executors are enabled.
+#[cfg(feature = "tokio")]
+fn default_spawner() -> Option<Arc<dyn Spawner>> {
+    Some(Arc::new(TokioSpawner))
+}
+
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+fn default_spawner() -> Option<Arc<dyn Spawner>> {
+    Some(Arc::new(SmolSpawner))
+}
+
+#[cfg(not(any(feature = "tokio", feature = "smol")))]
+fn default_spawner() -> Option<Arc<dyn Spawner>> {
+    None
+}
+
+impl SpawnerSlot {
+    pub fn set(&self, spawner: impl Spawner) {
+        *self.0.lock().expect("Spawner lock") = Some(Arc::new(spawner));
+    }
+
+    pub fn spawn<Fut>(&self, forked: Forked<Fut>) -> Result<JoinHandle<Fut::Output>, NoSpawner>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let spawner = self.0.lock().expect("Spawner lock").clone().ok_or(NoSpawner)?;
+
+        let (sender, output) = oneshot::channel();
+        let (abort, registration) = AbortHandle::new_pair();
+        let task = Abortable::new(forked, registration).map(|output| {
+            if let Ok(output) = output {
+                // The handle may be gone already, nobody waits for the output then.
+                let _ = sender.send(output);
+            }
+        });
+        spawner.spawn(task.boxed());
+
+        Ok(JoinHandle { output, abort })
+    }
+}
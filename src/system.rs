@@ -1,29 +1,156 @@
-use crate::{Query, QueryRef};
+use crate::{
+    Cancelled, Forked, Intern, InternId, JoinHandle, NoSpawner, Query, QueryError, QueryRef,
+    QueryTuple, Try, TryQuery,
+};
 use async_trait::async_trait;
-use futures::future::Abortable;
 use futures::Future;
+use std::collections::HashMap;
 use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 #[async_trait]
 pub trait System: Send + Sync + 'static {
     async fn query_ref<Q: Query>(&self, query: Q) -> QueryRef<Q::Output>;
 
+    /// Inside of queries, the calculation awaiting a cancelled query is cancelled and dropped too.
+    /// Outside of queries it's calculated again after the write cancelling it.
+    ///
+    /// # Panics
+    ///
+    /// Outside of queries, if the [scope](crate::Scope) or snapshot is cancelled.
+    /// [`System::query_cancellable`] resolves to [`Cancelled`] instead.
     async fn query<Q>(&self, query: Q) -> Q::Output
     where
         Q: Query,
         Q::Output: Clone;
 
-    async fn fork<F, T>(&self, f: F) -> Abortable<T>
+    /// Queries, or resolves to [`Cancelled`] if the query is cancelled.
+    async fn query_cancellable<Q>(&self, query: Q) -> Result<Q::Output, Cancelled>
+    where
+        Q: Query,
+        Q::Output: Clone;
+
+    /// Queries a fallible query, its error carries the queries it passed through.
+    async fn try_query<Q>(&self, query: Q) -> Result<Q::Ok, QueryError<Q::Error>>
+    where
+        Q: TryQuery,
+        Q::Ok: Clone,
+        Q::Error: Clone,
+    {
+        self.query(Try(query)).await
+    }
+
+    /// Queries concurrently, equal queries only once, each in its own fork. Outputs are in
+    /// the order of `queries`, so are dependencies of the calculated query.
+    async fn query_all<Q, I>(&self, queries: I) -> Vec<Q::Output>
+    where
+        Q: Query,
+        Q::Output: Clone,
+        I: IntoIterator<Item = Q> + Send,
+        I::IntoIter: Send,
+        Self: Sized,
+    {
+        let queries: Vec<Q> = queries.into_iter().collect();
+        // Index of each query among the distinct ones, which keep the first occurrence.
+        let positions: Vec<usize> = {
+            let mut distinct = HashMap::new();
+            let positions = queries.iter().map(|query| {
+                let next = distinct.len();
+                *distinct.entry(query).or_insert(next)
+            });
+            positions.collect()
+        };
+
+        let mut distinct = Vec::new();
+        for (query, &position) in queries.into_iter().zip(&positions) {
+            if position == distinct.len() {
+                distinct.push(query_forked(self, query));
+            }
+        }
+        let outputs = futures::future::join_all(distinct).await;
+
+        positions.into_iter().map(|position| outputs[position].clone()).collect()
+    }
+
+    /// Queries a tuple of up to 5 queries of different types concurrently, like `join!`,
+    /// each in its own fork.
+    async fn query_join<T: QueryTuple>(&self, queries: T) -> T::Output
+    where
+        Self: Sized,
+    {
+        queries.query_with(self).await
+    }
+
+    async fn intern<T: Intern>(&self, value: T) -> InternId<T>;
+
+    async fn lookup<T: Intern>(&self, id: InternId<T>) -> T;
+
+    async fn fork<F, T>(&self, f: F) -> Forked<T>
     where
         F: Send + Fn(Self) -> T,
         T: Future + Send,
         Self: Sized;
+
+    /// Forks and runs the fork on the runtime's [`Spawner`](crate::Spawner),
+    /// so queries don't depend on the executor. Fails without a spawner, as without the `tokio`
+    /// and `smol` features until one is set.
+    async fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T::Output>, NoSpawner>
+    where
+        F: Send + Fn(Self) -> T,
+        T: Future + Send + 'static,
+        T::Output: Send + 'static,
+        Self: Sized;
+
+    /// An input was written that the query has read so far, or a query waiting for it has.
+    /// It's `false` outside of queries, unless the [scope](crate::Scope) is canceled.
+    /// Snapshots are cancelled by writes under [`SnapshotPolicy::Cancel`](crate::SnapshotPolicy::Cancel).
+    fn is_cancelled(&self) -> bool;
+
+    /// Carries the cancellation of a fork or a [cancellable](System::query_cancellable) query
+    /// out of the query awaiting it. The query is cancelled, its calculation is dropped instead
+    /// of resolving this.
+    ///
+    /// # Panics
+    ///
+    /// Outside of queries, there is no calculation to drop.
+    async fn propagate<T: Send>(&self, cancelled: Cancelled) -> T;
+
+    /// Cancels the query if it [is cancelled](System::is_cancelled), nothing is memoized then.
+    /// Long calculations can await it to bail out early. Outside of queries it
+    /// [propagates](System::propagate) the cancellation of a scope or snapshot, so it panics.
+    async fn check_cancelled(&self) {
+        if self.is_cancelled() {
+            tracing::debug!("Query cancelled");
+            self.propagate(Cancelled).await
+        }
+    }
+}
+
+/// Queries in a fork of its own, so queries batched together wait for each other
+/// like forks do, instead of closing a cycle.
+pub(crate) async fn query_forked<S, Q>(system: &S, query: Q) -> Q::Output
+where
+    S: System,
+    Q: Query,
+    Q::Output: Clone,
+{
+    let query = Mutex::new(Some(query));
+    let forked = system
+        .fork(|system| {
+            let query = query.lock().expect("Query lock").take();
+            async move { system.query(query.expect("Forked once")).await }
+        })
+        .await;
+    match forked.await {
+        Ok(output) => output,
+        Err(cancelled) => system.propagate(cancelled).await,
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Ord, PartialOrd)]
-pub(crate) struct ForkId(usize);
+/// Identifies a fork, and the queries it calculates.
+#[derive(Clone, Copy, PartialEq, Eq, Ord, PartialOrd, Hash)]
+pub struct ForkId(usize);
 impl ForkId {
     pub(crate) fn new(counter: &Arc<AtomicUsize>) -> Self {
         let id = counter.fetch_add(1, Ordering::SeqCst);
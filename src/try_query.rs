@@ -0,0 +1,154 @@
+use crate::{Cycle, Query, System};
+use async_trait::async_trait;
+use core::hash::Hash;
+use std::fmt;
+
+/// Fallible query. Errors are memoized and tracked as dependencies just like outputs.
+#[async_trait]
+pub trait TryQuery: 'static + Send + Sync + Hash + PartialEq + Eq + fmt::Debug {
+    type Ok: Send + Sync + fmt::Debug + Eq;
+    type Error: Send + Sync + fmt::Debug + Eq;
+
+    /// See [`Query::LRU_CAPACITY`].
+    const LRU_CAPACITY: usize = 0;
+
+    /// See [`Query::CYCLE_ITERATIONS`].
+    const CYCLE_ITERATIONS: usize = 100;
+
+    /// How many times the calculation is repeated after failing with a transient error.
+    /// The last error is memoized like any other.
+    const RETRIES: usize = 0;
+
+    async fn try_calc<S: System>(&self, system: &S) -> Result<Self::Ok, QueryError<Self::Error>>;
+
+    /// See [`Query::on_cycle`], errors pass through the query like the ones of `try_calc`.
+    fn on_cycle(&self, cycle: &Cycle) -> Result<Self::Ok, QueryError<Self::Error>> {
+        panic!("Cycle detected: {}", cycle)
+    }
+
+    /// See [`Query::cycle_initial`].
+    fn cycle_initial(&self) -> Option<Result<Self::Ok, QueryError<Self::Error>>> {
+        None
+    }
+}
+
+/// Error of a fallible query with the queries it passed through, innermost first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueryError<E> {
+    error: E,
+    transient: bool,
+    chain: Vec<String>,
+}
+
+impl<E> QueryError<E> {
+    pub fn new(error: E) -> Self {
+        Self {
+            error,
+            transient: false,
+            chain: vec![],
+        }
+    }
+
+    /// Error that may go away when calculated again, see [`TryQuery::RETRIES`].
+    pub fn transient(error: E) -> Self {
+        Self {
+            transient: true,
+            ..Self::new(error)
+        }
+    }
+
+    pub fn error(&self) -> &E {
+        &self.error
+    }
+
+    pub fn into_error(self) -> E {
+        self.error
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.transient
+    }
+
+    /// Debug representations of the queries the error passed through, innermost first.
+    pub fn chain(&self) -> &[String] {
+        &self.chain
+    }
+
+    /// Converts the error, keeping the chain.
+    pub fn map<F>(self, f: impl FnOnce(E) -> F) -> QueryError<F> {
+        QueryError {
+            error: f(self.error),
+            transient: self.transient,
+            chain: self.chain,
+        }
+    }
+
+    fn passed(mut self, query: &impl fmt::Debug) -> Self {
+        self.chain.push(format!("{:?}", query));
+        self
+    }
+}
+
+impl<E> From<E> for QueryError<E> {
+    fn from(error: E) -> Self {
+        Self::new(error)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for QueryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        if !self.chain.is_empty() {
+            write!(f, " (in {})", self.chain.join(" <- "))?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for QueryError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Fallible query is calculated and memoized as a regular one, with `Result` as its output.
+#[derive(Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Try<Q>(pub(crate) Q);
+
+impl<Q: fmt::Debug> fmt::Debug for Try<Q> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[async_trait]
+impl<Q: TryQuery> Query for Try<Q> {
+    type Output = Result<Q::Ok, QueryError<Q::Error>>;
+
+    const LRU_CAPACITY: usize = Q::LRU_CAPACITY;
+    const CYCLE_ITERATIONS: usize = Q::CYCLE_ITERATIONS;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let mut retries = Q::RETRIES;
+        loop {
+            match self.0.try_calc(system).await {
+                Err(error) if error.is_transient() && retries > 0 => {
+                    tracing::debug!("Retrying {:?} after {:?}", self.0, error);
+                    retries -= 1;
+                }
+                out => return out.map_err(|error| error.passed(&self.0)),
+            }
+        }
+    }
+
+    fn on_cycle(&self, cycle: &Cycle) -> Self::Output {
+        self.0.on_cycle(cycle).map_err(|error| error.passed(&self.0))
+    }
+
+    fn cycle_initial(&self) -> Option<Self::Output> {
+        let initial = self.0.cycle_initial()?;
+        Some(initial.map_err(|error| error.passed(&self.0)))
+    }
+}
@@ -0,0 +1,95 @@
+use async_trait::async_trait;
+use futures::FutureExt;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Query, Runtime, System};
+use smol::Task;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct LongQuery;
+
+#[async_trait]
+impl Query for LongQuery {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let o = system.query(A).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        o
+    }
+}
+
+/// Panics the first time it's calculated.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Flaky;
+
+#[async_trait]
+impl Query for Flaky {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let o = system.query(A).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(100)).await;
+        if !PANICKED.swap(true, Ordering::SeqCst) {
+            panic!("Flaky");
+        }
+        o
+    }
+}
+
+async fn delay() {
+    tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
+}
+
+#[test]
+fn abandoned() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "a".into()).await;
+
+        tracing::info!("Calculating fork is dropped");
+        let scope = system.scope();
+        let dropped = scope
+            .fork(|system| Task::spawn(async move { system.query(LongQuery).await }))
+            .await;
+        delay().await;
+        let reader = system
+            .fork(|system| Task::spawn(async move { system.query(LongQuery).await }))
+            .await;
+        delay().await;
+        drop(dropped);
+
+        assert_eq!(reader.await, Ok("a".to_string()));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Processed count");
+
+        tracing::info!("Calculating fork panics");
+        let panicking = system
+            .fork(|system| {
+                Task::spawn(
+                    AssertUnwindSafe(async move { system.query(Flaky).await }).catch_unwind(),
+                )
+            })
+            .await;
+        delay().await;
+        let reader = system
+            .fork(|system| Task::spawn(async move { system.query(Flaky).await }))
+            .await;
+
+        assert!(panicking.await.unwrap().is_err());
+        assert_eq!(reader.await, Ok("a".to_string()));
+        assert_eq!(system.query(Flaky).await, "a");
+    });
+}
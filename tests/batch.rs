@@ -0,0 +1,108 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Query, Runtime, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct B;
+impl Input for B {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Add;
+
+#[async_trait]
+impl Query for Add {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let a = system.query(A).await;
+        let b = system.query(B).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        format!("{} + {}", a, b)
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn batch() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        let out = system
+            .batch(|tx| {
+                tx.set_input(A, "1".into());
+                tx.set_input(B, "2".into());
+                Ok::<_, ()>(3)
+            })
+            .await;
+        assert_eq!(out, Ok(3));
+        assert_eq!(format!("{:?}", system.current_rev()), "R1");
+        assert_query!(system, "R1", "1", A);
+        assert_query!(system, "R1", "1 + 2", Add);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Proccess count");
+
+        // Failed batch changes nothing
+        let out = system
+            .batch(|tx| {
+                tx.set_input(A, "3".into());
+                Err::<(), _>("Failed")
+            })
+            .await;
+        assert_eq!(out, Err("Failed"));
+        assert_eq!(format!("{:?}", system.current_rev()), "R1");
+        assert_query!(system, "R1", "1 + 2", Add);
+
+        // Later writes win
+        system
+            .batch(|tx| {
+                tx.set_input(A, "3".into());
+                tx.set_input(B, "4".into());
+                tx.set_input(A, "5".into());
+                Ok::<_, ()>(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(format!("{:?}", system.current_rev()), "R2");
+        assert_query!(system, "R2", "5 + 4", Add);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Proccess count");
+
+        // Removals go along with other changes
+        system
+            .batch(|tx| {
+                tx.remove_input(B);
+                tx.set_input(A, "6".into());
+                Ok::<_, ()>(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(format!("{:?}", system.current_rev()), "R3");
+        assert_query!(system, "R3", "6", A);
+
+        // Empty batch doesn't create a revision
+        system.batch(|_| Ok::<_, ()>(())).await.unwrap();
+        assert_eq!(format!("{:?}", system.current_rev()), "R3");
+    });
+}
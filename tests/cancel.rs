@@ -1,10 +1,11 @@
 use async_trait::async_trait;
+use futures::FutureExt;
 use guacamole::test_common::init_log;
-use guacamole::{Input, Query, Runtime, System};
+use guacamole::{Cancelled, Input, Query, Runtime, System};
 use smol::Task;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::panic::AssertUnwindSafe;
-use futures::FutureExt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 
 static PROCESSED: AtomicUsize = AtomicUsize::new(0);
 
@@ -42,17 +43,100 @@ impl Query for Add {
     type Output = String;
 
     async fn calc<S: System>(&self, system: &S) -> Self::Output {
-        system
+        let forked = system
             .fork(|system| Task::spawn(async move { system.query(LongQuery(A)).await }))
-            .await
-            .await
-            .unwrap()
+            .await;
+        match forked.await {
+            Ok(output) => output,
+            Err(cancelled) => system.propagate(cancelled).await,
+        }
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct B;
+impl Input for B {
+    type Data = String;
+}
+
+/// Like `LongQuery`, without counting.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Slow<Q: Query>(Q);
+
+#[async_trait]
+impl<Q> Query for Slow<Q>
+where
+    Q: Query + Clone,
+    Q::Output: Clone,
+{
+    type Output = Q::Output;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let o = system.query(self.0.clone()).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+        o
+    }
+}
+
+static SPINNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Spin;
+#[async_trait]
+impl Query for Spin {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let a = system.query(A).await;
+        // Never awaits again, only the check can stop it.
+        if a == "spin" {
+            SPINNING.store(true, Ordering::SeqCst);
+            loop {
+                system.check_cancelled().await;
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+        assert!(!system.is_cancelled());
+        a
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Start;
+impl Input for Start {
+    type Data = usize;
+}
+
+/// Chain of `n` queries down to `Start`.
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Chain(usize);
+#[async_trait]
+impl Query for Chain {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        match self.0 {
+            0 => system.query(Start).await,
+            n => system.query(Chain(n - 1)).await,
+        }
+    }
+}
+
+/// Gives up without any write cancelling it.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct GiveUp;
+#[async_trait]
+impl Query for GiveUp {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.propagate(Cancelled).await
     }
 }
 
 macro_rules! assert_query {
     ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
-        let (out, rev) = $system.query_rev($query).await;
+        let (out, rev) = $system.query_rev($query).await.unwrap();
         assert_eq!(
             format!("{:?}", rev),
             $rev,
@@ -73,16 +157,8 @@ fn cancel_smol() {
 
         assert_query!(system, "R1", "2", A);
 
-        let handle_once =
-            system
-            .fork(|system| Task::spawn(
-                {
-                    AssertUnwindSafe(
-                        async move { system.query(Add).await }
-                    )
-                        .catch_unwind()
-                }
-            ))
+        let handle_once = system
+            .fork(|system| Task::spawn(async move { system.query(Add).await }))
             .await;
 
         tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
@@ -92,7 +168,7 @@ fn cancel_smol() {
         let out = handle_once.await;
 
         tracing::info!("OUTPUT {:?}", out);
-        assert!(out.is_err());
+        assert_eq!(out, Err(Cancelled));
 
         tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
         assert_eq!(PROCESSED.load(Ordering::SeqCst), 0, "Processed count");
@@ -107,3 +183,95 @@ fn cancel_smol() {
         assert_eq!("3", out);
     });
 }
+
+#[test]
+fn check_cancelled() {
+    let system = Arc::new(Runtime::default());
+    smol::run(system.set_input(A, "spin".into()));
+
+    let writer = {
+        let system = system.clone();
+        std::thread::spawn(move || {
+            while !SPINNING.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+            smol::run(system.set_input(A, "done".into()));
+        })
+    };
+
+    assert!(!system.is_cancelled());
+    let out = smol::run(system.query_rev(Spin));
+    assert_eq!(out, Err(Cancelled));
+    writer.join().unwrap();
+
+    smol::run(async move {
+        assert_query!(system, "R2", "done", Spin);
+    });
+}
+
+#[test]
+fn cancel_selective() {
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "2".into()).await;
+        system.set_input(B, "5".into()).await;
+
+        let handle_a = system
+            .fork(|system| Task::spawn(async move { system.query(Slow(A)).await }))
+            .await;
+        let handle_b = system
+            .fork(|system| Task::spawn(async move { system.query(Slow(B)).await }))
+            .await;
+
+        tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
+
+        tracing::info!("Only the fork that has read A is canceled");
+        system.set_input(A, "3".into()).await;
+        assert_eq!(handle_a.await, Err(Cancelled));
+        assert_eq!(handle_b.await, Ok("5".to_string()));
+
+        assert_query!(system, "R3", "3", Slow(A));
+        assert_query!(system, "R2", "5", Slow(B));
+    });
+}
+
+#[test]
+fn cancel_deep() {
+    const DEPTH: usize = 20_000;
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(Start, 1).await;
+        system.set_input(B, "5".into()).await;
+        // Calculated bottom up, so no calculation goes deep.
+        for n in 0..=DEPTH {
+            system.query(Chain(n)).await;
+        }
+
+        let handle_chain = system
+            .fork(|system| Task::spawn(async move { system.query(Slow(Chain(DEPTH))).await }))
+            .await;
+        let handle_b = system
+            .fork(|system| Task::spawn(async move { system.query(Slow(B)).await }))
+            .await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
+
+        tracing::info!("Writes walk the whole chain to find out it's not affected");
+        system.set_input(B, "6".into()).await;
+        assert_eq!(handle_b.await, Err(Cancelled));
+        assert_eq!(handle_chain.await, Ok(1));
+    });
+}
+
+#[test]
+fn give_up() {
+    let system = Runtime::default();
+    smol::run(async move {
+        assert_eq!(system.query_cancellable(GiveUp).await, Err(Cancelled));
+
+        tracing::info!("Nothing was written, so it's not calculated again");
+        let panicked = AssertUnwindSafe(system.query(GiveUp)).catch_unwind().await;
+        let message = panicked.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("Query cancelled"), "{}", message);
+    });
+}
@@ -1,6 +1,8 @@
 use async_trait::async_trait;
 use guacamole::test_common::init_log;
-use guacamole::{Input, Query, Runtime, System};
+use guacamole::{Cycle, Input, Query, Runtime, System};
+use smol::Task;
+use std::sync::Mutex;
 
 #[derive(Hash, PartialEq, Eq, Debug, Clone)]
 struct File;
@@ -20,14 +22,114 @@ impl Query for Count {
         system.query(Count).await
     }
 
-    fn on_cycle(&self) -> Option<()> {
+    fn on_cycle(&self, _cycle: &Cycle) -> Option<()> {
         None
     }
 }
 
+static CYCLES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Outer(u32);
+#[async_trait]
+impl Query for Outer {
+    type Output = u32;
+
+    async fn calc<S: System>(&self, system: &S) -> u32 {
+        system.query(Inner(self.0)).await
+    }
+
+    fn on_cycle(&self, cycle: &Cycle) -> u32 {
+        CYCLES.lock().unwrap().push(cycle.to_string());
+        cycle.queries().len() as u32
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Inner(u32);
+#[async_trait]
+impl Query for Inner {
+    type Output = u32;
+
+    async fn calc<S: System>(&self, system: &S) -> u32 {
+        match self.0 {
+            0 => system.query(Outer(1)).await,
+            n => system.query(Inner(n - 1)).await,
+        }
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Loop;
+#[async_trait]
+impl Query for Loop {
+    type Output = ();
+
+    async fn calc<S: System>(&self, system: &S) {
+        system.query(Loop).await
+    }
+}
+
+static FORK_CYCLES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+async fn delay() {
+    tokio::time::delay_for(tokio::time::Duration::from_millis(20)).await;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Ping;
+#[async_trait]
+impl Query for Ping {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> String {
+        delay().await;
+        format!("ping {}", system.query(Pong).await)
+    }
+
+    fn on_cycle(&self, cycle: &Cycle) -> String {
+        FORK_CYCLES.lock().unwrap().push(cycle.to_string());
+        "?".into()
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Pong;
+#[async_trait]
+impl Query for Pong {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> String {
+        delay().await;
+        format!("pong {}", system.query(Ping).await)
+    }
+
+    fn on_cycle(&self, cycle: &Cycle) -> String {
+        FORK_CYCLES.lock().unwrap().push(cycle.to_string());
+        "?".into()
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct PingPong;
+#[async_trait]
+impl Query for PingPong {
+    type Output = (String, String);
+
+    async fn calc<S: System>(&self, system: &S) -> (String, String) {
+        let ping = system
+            .fork(|system| Task::spawn(async move { system.query(Ping).await }))
+            .await;
+        let pong = system
+            .fork(|system| Task::spawn(async move { system.query(Pong).await }))
+            .await;
+        futures::future::try_join(ping, pong).await.unwrap()
+    }
+}
+
 macro_rules! assert_query {
     ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
-        let (out, rev) = $system.query_rev($query).await;
+        let (out, rev) = $system.query_rev($query).await.unwrap();
         assert_eq!(
             format!("{:?}", rev),
             $rev,
@@ -52,3 +154,57 @@ fn cycle() {
         assert_query!(system, "R1", None, Count);
     });
 }
+
+#[test]
+fn cycle_path() {
+    let system = Runtime::default();
+    smol::run(async move {
+        tracing::info!("Entered at the outer query");
+        assert_query!(system, "R0", 3, Outer(1));
+        assert_eq!(
+            CYCLES.lock().unwrap().as_slice(),
+            [
+                "Outer(1) (cycle::Outer) -> Inner(1) (cycle::Inner) -> Inner(0) (cycle::Inner) \
+              -> Outer(1) (cycle::Outer)"
+            ]
+        );
+    });
+}
+
+#[test]
+fn cycle_panic() {
+    let system = Runtime::default();
+    let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        smol::run(async move { system.query(Loop).await })
+    }))
+    .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<String>().map(String::as_str),
+        Some("Cycle detected: Loop (cycle::Loop) -> Loop (cycle::Loop)")
+    );
+}
+
+#[test]
+fn fork_cycle() {
+    let system = Runtime::default();
+    smol::run(async move {
+        tracing::info!("Forks waiting for each other");
+        // Whichever fork waits last sees the cycle.
+        let outputs = system.query(PingPong).await;
+        let cycles = FORK_CYCLES.lock().unwrap().clone();
+        let ping = ("ping pong ?".to_string(), "pong ?".to_string());
+        let pong = ("ping ?".to_string(), "pong ping ?".to_string());
+        if outputs == ping {
+            assert_eq!(
+                cycles,
+                ["Ping (cycle::Ping) -> Pong (cycle::Pong) -> Ping (cycle::Ping)"]
+            );
+        } else {
+            assert_eq!(outputs, pong);
+            assert_eq!(
+                cycles,
+                ["Pong (cycle::Pong) -> Ping (cycle::Ping) -> Pong (cycle::Pong)"]
+            );
+        }
+    });
+}
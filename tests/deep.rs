@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Query, Runtime, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Start;
+impl Input for Start {
+    type Data = usize;
+}
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Step(usize);
+#[async_trait]
+impl Query for Step {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        match self.0 {
+            0 => system.query(Start).await,
+            n => system.query(Step(n - 1)).await + 1,
+        }
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn deep() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(Start, 0).await;
+
+        assert_query!(system, "R1", 100, Step(100));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 101, "Processed count");
+
+        // Reuse memoized output
+        assert_query!(system, "R1", 100, Step(100));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 101, "Processed count");
+
+        system.set_input(Start, 1).await;
+        assert_query!(system, "R2", 101, Step(100));
+        assert_query!(system, "R2", 51, Step(50));
+    });
+}
@@ -70,7 +70,7 @@ impl Query for Add {
 
 macro_rules! assert_query {
     ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
-        let (out, rev) = $system.query_rev($query).await;
+        let (out, rev) = $system.query_rev($query).await.unwrap();
         assert_eq!(
             format!("{:?}", rev),
             $rev,
@@ -93,5 +93,10 @@ fn diamond() {
 
         assert_query!(system, "R1", "22 + 22", Add);
         assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Proccess count");
+
+        // Shared bottom is verified and recalculated once, for both paths
+        system.set_input(A, "3".into()).await;
+        assert_query!(system, "R2", "32 + 32", Add);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Proccess count");
     });
 }
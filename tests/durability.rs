@@ -0,0 +1,98 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Durability, Input, Query, Runtime, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PRELUDE: AtomicUsize = AtomicUsize::new(0);
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Std;
+impl Input for Std {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct File;
+impl Input for File {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Prelude;
+
+#[async_trait]
+impl Query for Prelude {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let std = system.query_ref(Std).await;
+        PRELUDE.fetch_add(1, Ordering::SeqCst);
+        format!("use {}", *std)
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Program;
+
+#[async_trait]
+impl Query for Program {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let prelude = system.query(Prelude).await;
+        let file = system.query_ref(File).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        format!("{}; {}", prelude, *file)
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn durability() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system
+            .set_input_with_durability(Std, "std".into(), Durability::High)
+            .await;
+        system.set_input(File, "main".into()).await;
+
+        assert_query!(system, "R2", "use std; main", Program);
+        assert_eq!(PRELUDE.load(Ordering::SeqCst), 1, "Prelude count");
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Processed count");
+
+        tracing::info!("Low durability change");
+        system.set_input(File, "main2".into()).await;
+        assert_query!(system, "R3", "use std; main2", Program);
+        assert_query!(system, "R1", "use std", Prelude);
+        assert_eq!(PRELUDE.load(Ordering::SeqCst), 1, "Prelude count");
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Processed count");
+
+        tracing::info!("High durability change");
+        system
+            .set_input_with_durability(Std, "core".into(), Durability::High)
+            .await;
+        assert_query!(system, "R4", "use core; main2", Program);
+        assert_eq!(PRELUDE.load(Ordering::SeqCst), 2, "Prelude count");
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 3, "Processed count");
+
+        tracing::info!("Lowering durability");
+        system.set_input(Std, "alloc".into()).await;
+        assert_query!(system, "R5", "use alloc", Prelude);
+        assert_eq!(PRELUDE.load(Ordering::SeqCst), 3, "Prelude count");
+    });
+}
@@ -45,7 +45,7 @@ impl Query for ProcessCounted {
 
 macro_rules! assert_query {
     ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
-        let (out, rev) = $system.query_rev($query).await;
+        let (out, rev) = $system.query_rev($query).await.unwrap();
         assert_eq!(
             format!("{:?}", rev),
             $rev,
@@ -0,0 +1,116 @@
+use async_trait::async_trait;
+use futures::FutureExt;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Query, Runtime, System};
+use std::collections::BTreeSet;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Edges(u32);
+impl Input for Edges {
+    type Data = Vec<u32>;
+}
+
+/// Nodes reachable from the node, the graph may have cycles.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Reach(u32);
+
+#[async_trait]
+impl Query for Reach {
+    type Output = BTreeSet<u32>;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        let mut reach: BTreeSet<_> = std::iter::once(self.0).collect();
+        for next in system.query(Edges(self.0)).await {
+            reach.extend(system.query(Reach(next)).await);
+        }
+        reach
+    }
+
+    fn cycle_initial(&self) -> Option<Self::Output> {
+        Some(BTreeSet::new())
+    }
+}
+
+/// Never converges.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Grow;
+
+#[async_trait]
+impl Query for Grow {
+    type Output = usize;
+
+    const CYCLE_ITERATIONS: usize = 10;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query(Grow).await + 1
+    }
+
+    fn cycle_initial(&self) -> Option<Self::Output> {
+        Some(0)
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+fn set(nodes: &[u32]) -> BTreeSet<u32> {
+    nodes.iter().copied().collect()
+}
+
+#[test]
+fn fixpoint() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system
+            .batch(|tx| {
+                tx.set_input(Edges(1), vec![2]);
+                tx.set_input(Edges(2), vec![3]);
+                tx.set_input(Edges(3), vec![1, 4]);
+                tx.set_input(Edges(4), vec![]);
+                Ok::<_, ()>(())
+            })
+            .await
+            .unwrap();
+
+        // 1 -> 2 -> 3 -> 1 is calculated twice, the second time it doesn't change
+        assert_query!(system, "R1", set(&[1, 2, 3, 4]), Reach(1));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 7, "Proccess count");
+
+        // Participants are memoized with the converged output
+        assert_query!(system, "R1", set(&[1, 2, 3, 4]), Reach(3));
+        assert_query!(system, "R1", set(&[1, 2, 3, 4]), Reach(2));
+        assert_query!(system, "R1", set(&[4]), Reach(4));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 7, "Proccess count");
+
+        // Unrelated change keeps the cycle
+        system.set_input(Edges(4), vec![4]).await;
+        assert_query!(system, "R2", set(&[4]), Reach(4));
+        assert_query!(system, "R2", set(&[1, 2, 3, 4]), Reach(1));
+
+        // Cycle is broken
+        system.set_input(Edges(3), vec![4]).await;
+        assert_query!(system, "R3", set(&[3, 4]), Reach(3));
+        assert_query!(system, "R3", set(&[2, 3, 4]), Reach(2));
+        assert_query!(system, "R3", set(&[1, 2, 3, 4]), Reach(1));
+
+        let grow = AssertUnwindSafe(system.query(Grow)).catch_unwind().await;
+        assert!(grow.is_err(), "Cycle doesn't converge");
+    });
+}
@@ -0,0 +1,67 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Query, Runtime, System};
+use smol::Task;
+use std::sync::Mutex;
+
+static CALCULATED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = usize;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Times(usize);
+#[async_trait]
+impl Query for Times {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        CALCULATED.lock().unwrap().push(self.0);
+        self.0 * system.query(A).await
+    }
+}
+
+/// The first fork starts reading last.
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Forks;
+#[async_trait]
+impl Query for Forks {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let late = system
+            .fork(|system| {
+                Task::spawn(async move {
+                    tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
+                    system.query(Times(1)).await
+                })
+            })
+            .await;
+        let early = system
+            .fork(|system| Task::spawn(async move { system.query(Times(2)).await }))
+            .await;
+        let own = system.query(Times(3)).await;
+
+        late.await.unwrap() + early.await.unwrap() + own
+    }
+}
+
+#[test]
+fn fork_deps() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, 1).await;
+        assert_eq!(system.query(Forks).await, 6);
+        CALCULATED.lock().unwrap().clear();
+
+        tracing::info!("Deps of forks are verified in the order the forks were created");
+        system.set_input(A, 2).await;
+        assert_eq!(system.query(Forks).await, 12);
+        assert_eq!(CALCULATED.lock().unwrap()[0], 1);
+    });
+}
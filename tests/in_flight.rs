@@ -0,0 +1,63 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Query, QueryInfo, Runtime, System};
+use smol::Task;
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct LongQuery;
+
+#[async_trait]
+impl Query for LongQuery {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let o = system.query(A).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+        o
+    }
+}
+
+#[test]
+fn in_flight() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "a".into()).await;
+
+        tracing::info!("Completed forks are pruned");
+        for _ in 0..100 {
+            let fork = system
+                .fork(|system| Task::spawn(async move { system.query(A).await }))
+                .await;
+            assert_eq!(fork.await, Ok("a".to_string()));
+        }
+        assert_eq!(system.in_flight().await, vec![]);
+
+        tracing::info!("Long query in flight");
+        let fork = system
+            .fork(|system| Task::spawn(async move { system.query(LongQuery).await }))
+            .await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
+
+        let in_flight = system.in_flight().await;
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(format!("{:?}", in_flight[0].started_at), "R1");
+        assert_eq!(
+            in_flight[0].query,
+            Some(QueryInfo {
+                type_name: "in_flight::LongQuery",
+                key: "LongQuery".into(),
+            })
+        );
+
+        assert_eq!(fork.await, Ok("a".to_string()));
+        assert_eq!(system.in_flight().await, vec![]);
+    });
+}
@@ -0,0 +1,83 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Durability, Input, Query, Runtime, System};
+use smol::Task;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct LongQuery;
+
+#[async_trait]
+impl Query for LongQuery {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let o = system.query(A).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(100)).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        o
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn input_eq() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        assert!(system.set_input(A, "2".into()).await, "Input set");
+        assert_query!(system, "R1", "2", LongQuery);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Proccess count");
+
+        // Equal value neither creates a revision nor cancels ongoing requests
+        system.set_input(A, "3".into()).await;
+        let handle = system
+            .fork(|system| Task::spawn(async move { system.query(LongQuery).await }))
+            .await;
+        assert!(!system.set_input(A, "3".into()).await, "Input unchanged");
+        assert_eq!(handle.await, Ok("3".to_string()));
+        assert_eq!(format!("{:?}", system.current_rev()), "R2");
+        assert_query!(system, "R2", "3", LongQuery);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Proccess count");
+
+        // Different durability is a change
+        assert!(
+            system
+                .set_input_with_durability(A, "3".into(), Durability::High)
+                .await,
+            "Durability changed"
+        );
+        assert_eq!(format!("{:?}", system.current_rev()), "R3");
+
+        // Batch of equal values doesn't create a revision either
+        system
+            .batch(|tx| {
+                tx.set_input_with_durability(A, "3".into(), Durability::High);
+                Ok::<_, ()>(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(format!("{:?}", system.current_rev()), "R3");
+    });
+}
@@ -0,0 +1,104 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Intern, InternId, Query, Runtime, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Path(String);
+impl Intern for Path {}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct File(InternId<Path>);
+impl Input for File {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Describe(InternId<Path>);
+
+#[async_trait]
+impl Query for Describe {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let path = system.lookup(self.0).await;
+        let file = system.query_ref(File(self.0)).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        format!("{}: {}", path.0, *file)
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct DescribePath(String);
+
+#[async_trait]
+impl Query for DescribePath {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let id = system.intern(Path(self.0.clone())).await;
+        system.query(Describe(id)).await
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn interning() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        let main = system.intern(Path("main.rs".into())).await;
+        let lib = system.intern(Path("lib.rs".into())).await;
+        assert_ne!(main, lib);
+        assert_eq!(main, system.intern(Path("main.rs".into())).await);
+        assert_eq!(Path("lib.rs".into()), system.lookup(lib).await);
+
+        system.set_input(File(main), "fn main() {}".into()).await;
+        system.set_input(File(lib), "pub fn lib() {}".into()).await;
+
+        assert_query!(system, "R1", "main.rs: fn main() {}", Describe(main));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Processed count");
+
+        // Interning the same value again gives the same id, so the output is memoized
+        assert_query!(
+            system,
+            "R1",
+            "main.rs: fn main() {}",
+            DescribePath("main.rs".into())
+        );
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Processed count");
+
+        system.set_input(File(lib), "pub fn lib2() {}".into()).await;
+        assert_query!(
+            system,
+            "R3",
+            "lib.rs: pub fn lib2() {}",
+            DescribePath("lib.rs".into())
+        );
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Processed count");
+
+        // Interned values never change, so the dependency stays fresh
+        assert_query!(
+            system,
+            "R1",
+            "main.rs: fn main() {}",
+            DescribePath("main.rs".into())
+        );
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Processed count");
+    });
+}
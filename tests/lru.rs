@@ -0,0 +1,99 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Query, Runtime, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+static SUMMED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct File;
+impl Input for File {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Other;
+impl Input for Other {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Len(usize);
+
+#[async_trait]
+impl Query for Len {
+    type Output = usize;
+
+    const LRU_CAPACITY: usize = 2;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let file = system.query_ref(File).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        file.len() + self.0
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Sum;
+
+#[async_trait]
+impl Query for Sum {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let mut sum = 0;
+        for n in 1..=3 {
+            sum += system.query(Len(n)).await;
+        }
+        SUMMED.fetch_add(1, Ordering::SeqCst);
+        sum
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn lru() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(File, "abc".into()).await;
+        system.set_input(Other, "1".into()).await;
+
+        // Len(1) is evicted after Len(3) is calculated
+        assert_query!(system, "R1", 15, Sum);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 3, "Processed count");
+        assert_eq!(SUMMED.load(Ordering::SeqCst), 1, "Summed count");
+
+        // Outputs still in the cache are reused
+        assert_query!(system, "R1", 6, Len(3));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 3, "Processed count");
+
+        // Evicted output is recalculated, but keeps its revision
+        assert_query!(system, "R1", 4, Len(1));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 4, "Processed count");
+
+        // Len(2) got evicted now, yet Sum doesn't need its output to stay fresh
+        system.set_input(Other, "2".into()).await;
+        assert_query!(system, "R1", 15, Sum);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 4, "Processed count");
+        assert_eq!(SUMMED.load(Ordering::SeqCst), 1, "Summed count");
+
+        system.set_input(File, "abcd".into()).await;
+        assert_query!(system, "R4", 18, Sum);
+        assert_eq!(SUMMED.load(Ordering::SeqCst), 2, "Summed count");
+    });
+}
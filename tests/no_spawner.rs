@@ -0,0 +1,22 @@
+#![cfg(not(any(feature = "tokio", feature = "smol")))]
+
+use guacamole::{Input, NoSpawner, Runtime, System};
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = usize;
+}
+
+#[test]
+fn no_spawner() {
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, 2).await;
+
+        let spawned = system
+            .spawn(|system| async move { system.query(A).await })
+            .await;
+        assert!(matches!(spawned, Err(NoSpawner)));
+    });
+}
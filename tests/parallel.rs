@@ -69,7 +69,7 @@ impl Query for Add {
 
 macro_rules! assert_query {
     ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
-        let (out, rev) = $system.query_rev($query).await;
+        let (out, rev) = $system.query_rev($query).await.unwrap();
         assert_eq!(
             format!("{:?}", rev),
             $rev,
@@ -110,3 +110,56 @@ fn parallel() {
         assert_query!(system, "R3", "X + 3 + 4", Add { c: 4 });
     });
 }
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Square(usize);
+#[async_trait]
+impl Query for Square {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let a = system.query(LongQuery(A)).await;
+        a.parse::<usize>().unwrap() * self.0 * self.0
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct SumSquares(usize);
+#[async_trait]
+impl Query for SumSquares {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let mut handles = vec![];
+        for n in 0..self.0 {
+            let handle = system
+                .fork(move |system| Task::spawn(async move { system.query(Square(n)).await }))
+                .await;
+            handles.push(handle);
+        }
+
+        let squares = futures::future::try_join_all(handles).await.unwrap();
+        squares.into_iter().sum()
+    }
+}
+
+#[test]
+fn parallel_many_forks() {
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "2".into()).await;
+        system.set_input(B, "3".into()).await;
+
+        // All forks wait on the same LongQuery(A) reservation
+        let (sum, add) =
+            futures::future::join(system.query(SumSquares(16)), system.query(Add { c: 5 })).await;
+        assert_eq!(sum, 2480);
+        assert_eq!(add, "2 + 3 + 5");
+
+        assert_query!(system, "R1", 2480, SumSquares(16));
+
+        // Each fork sees the shared LongQuery(A) has changed, even if another one recalculated it
+        system.set_input(A, "3".into()).await;
+        assert_eq!(system.query(SumSquares(16)).await, 3720);
+    });
+}
@@ -0,0 +1,230 @@
+#![cfg(feature = "serde")]
+
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Durability, Input, Intern, InternId, Persist, Query, Runtime, System};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PARSED: AtomicUsize = AtomicUsize::new(0);
+static SUMMED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct File(String);
+impl Input for File {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Parse(String);
+
+#[async_trait]
+impl Query for Parse {
+    type Output = Vec<usize>;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        PARSED.fetch_add(1, Ordering::SeqCst);
+        system
+            .query_ref(File(self.0.clone()))
+            .await
+            .split('+')
+            .map(|n| n.trim().parse().unwrap())
+            .collect()
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+struct Name(String);
+impl Intern for Name {}
+
+// Not persisted, so everything depending on it is recalculated after load.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Sum(String);
+
+#[async_trait]
+impl Query for Sum {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        SUMMED.fetch_add(1, Ordering::SeqCst);
+        system.query_ref(Parse(self.0.clone())).await.iter().sum()
+    }
+}
+
+/// Data that fails to serialize.
+#[derive(PartialEq, Eq, Debug, Deserialize)]
+struct Opaque;
+impl Serialize for Opaque {
+    fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+        Err(serde::ser::Error::custom("Opaque data"))
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Secret;
+impl Input for Secret {
+    type Data = Opaque;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct A;
+impl Input for A {
+    type Data = usize;
+}
+
+// Not persisted, in between persisted ones.
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Z;
+#[async_trait]
+impl Query for Z {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query(A).await * 10
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Y;
+#[async_trait]
+impl Query for Y {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query(Z).await
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct X;
+#[async_trait]
+impl Query for X {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query(Y).await + 1
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Author(String);
+impl Input for Author {
+    type Data = InternId<Name>;
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn persist() {
+    init_log();
+
+    let path = std::env::temp_dir().join(format!("guacamole-persist-{}.db", std::process::id()));
+    let persist = || {
+        Persist::default()
+            .with::<File>()
+            .with::<Parse>()
+            .with_interned::<Name>()
+    };
+
+    smol::run(async move {
+        let system = Runtime::default();
+        system
+            .set_input_with_durability(File("a".into()), "1 + 2".into(), Durability::High)
+            .await;
+        system.set_input(File("b".into()), "3 + 4".into()).await;
+
+        assert_query!(system, "R1", vec![1, 2], Parse("a".into()));
+        assert_query!(system, "R2", 7, Sum("b".into()));
+        assert_eq!(PARSED.load(Ordering::SeqCst), 2, "Parsed count");
+        assert_eq!(SUMMED.load(Ordering::SeqCst), 1, "Summed count");
+        let name = system.intern(Name("a".into())).await;
+
+        system.save(&persist(), &path).unwrap();
+        drop(system);
+
+        tracing::info!("Load");
+        let system = Runtime::load(&persist(), &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(Name("a".into()), system.lookup(name).await);
+        assert_query!(system, "R1", "1 + 2", File("a".into()));
+        assert_query!(system, "R1", vec![1, 2], Parse("a".into()));
+        assert_query!(system, "R2", vec![3, 4], Parse("b".into()));
+        assert_eq!(PARSED.load(Ordering::SeqCst), 2, "Parsed count");
+
+        assert_query!(system, "R2", 7, Sum("b".into()));
+        assert_eq!(PARSED.load(Ordering::SeqCst), 2, "Parsed count");
+        assert_eq!(SUMMED.load(Ordering::SeqCst), 2, "Summed count");
+
+        tracing::info!("Change after load");
+        system.set_input(File("b".into()), "3 + 5".into()).await;
+        assert_query!(system, "R3", vec![3, 5], Parse("b".into()));
+        assert_eq!(PARSED.load(Ordering::SeqCst), 3, "Parsed count");
+    });
+}
+
+#[test]
+fn save_error() {
+    let path = std::env::temp_dir().join(format!("guacamole-error-{}.db", std::process::id()));
+
+    smol::run(async move {
+        let system = Runtime::default();
+        system.set_input(Secret, Opaque).await;
+
+        let saved = system.save(&Persist::default().with::<Secret>(), &path);
+        assert_eq!(saved.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(!path.exists());
+    });
+}
+
+#[test]
+fn dependents_of_not_persisted() {
+    let path = std::env::temp_dir().join(format!("guacamole-xyz-{}.db", std::process::id()));
+    let persist = || Persist::default().with::<A>().with::<Y>().with::<X>();
+
+    smol::run(async move {
+        let system = Runtime::default();
+        system.set_input(A, 1).await;
+        assert_query!(system, "R1", 11, X);
+
+        system.save(&persist(), &path).unwrap();
+        drop(system);
+
+        let system = Runtime::load(&persist(), &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        system.set_input(A, 2).await;
+        assert_query!(system, "R2", 21, X);
+        assert_query!(system, "R2", 20, Y);
+    });
+}
+
+#[test]
+fn interned_not_persisted() {
+    let path = std::env::temp_dir().join(format!("guacamole-intern-{}.db", std::process::id()));
+
+    smol::run(async move {
+        let system = Runtime::default();
+        let name = system.intern(Name("a".into())).await;
+        system.set_input(Author("a".into()), name).await;
+
+        let saved = system.save(&Persist::default().with::<Author>(), &path);
+        assert_eq!(saved.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(!path.exists());
+
+        let persist = Persist::default().with::<Author>().with_interned::<Name>();
+        system.save(&persist, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    });
+}
@@ -0,0 +1,150 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Query, Runtime, System};
+use std::sync::Mutex;
+
+static CALCULATED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = usize;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Name;
+impl Input for Name {
+    type Data = String;
+}
+
+/// Takes longer the greater `n` is.
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Slow(usize);
+#[async_trait]
+impl Query for Slow {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        CALCULATED.lock().unwrap().push(self.0);
+        let a = system.query(A).await;
+        let delay = 20 * self.0 as u64;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(delay)).await;
+        a * self.0
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct All(Vec<usize>);
+#[async_trait]
+impl Query for All {
+    type Output = Vec<usize>;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query_all(self.0.iter().map(|n| Slow(*n))).await
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Joined;
+#[async_trait]
+impl Query for Joined {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let (name, slow) = system.query_join((Name, Slow(1))).await;
+        format!("{} {}", name, slow)
+    }
+}
+
+/// Read by every `Item`, the batch waits while one of them calculates it.
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Shared;
+#[async_trait]
+impl Query for Shared {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let a = system.query(A).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
+        a * 10
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Item(usize);
+#[async_trait]
+impl Query for Item {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query(Shared).await + self.0
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Items(Vec<usize>);
+#[async_trait]
+impl Query for Items {
+    type Output = Vec<usize>;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query_all(self.0.iter().map(|n| Item(*n))).await
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct JoinedItems;
+#[async_trait]
+impl Query for JoinedItems {
+    type Output = (usize, usize);
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query_join((Item(1), Item(2))).await
+    }
+}
+
+fn calculated() -> Vec<usize> {
+    std::mem::take(&mut *CALCULATED.lock().unwrap())
+}
+
+#[test]
+fn query_all() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, 2).await;
+        system.set_input(Name, "slow".into()).await;
+
+        tracing::info!("Duplicates are calculated once");
+        let all = system.query(All(vec![3, 1, 3, 2, 1])).await;
+        assert_eq!(all, vec![6, 2, 6, 4, 2]);
+        let mut all_calculated = calculated();
+        all_calculated.sort_unstable();
+        assert_eq!(all_calculated, vec![1, 2, 3]);
+
+        tracing::info!("Deps are verified in the order of queries, not of completion");
+        system.set_input(A, 3).await;
+        let all = system.query(All(vec![3, 1, 3, 2, 1])).await;
+        assert_eq!(all, vec![9, 3, 9, 6, 3]);
+        assert_eq!(calculated()[0], 3);
+
+        assert_eq!(system.query(Joined).await, "slow 3");
+    });
+}
+
+#[test]
+fn shared_dependency() {
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, 1).await;
+
+        tracing::info!("Queries of a batch waiting for the same query are no cycle");
+        assert_eq!(system.query(Items(vec![1, 2, 1])).await, vec![11, 12, 11]);
+        assert_eq!(system.query(JoinedItems).await, (11, 12));
+
+        system.set_input(A, 2).await;
+        assert_eq!(system.query(JoinedItems).await, (21, 22));
+        assert_eq!(system.query(Items(vec![3, 2])).await, vec![23, 22]);
+    });
+}
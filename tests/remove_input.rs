@@ -0,0 +1,110 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Intern, Query, Runtime, System};
+use itertools::Itertools;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTED: AtomicUsize = AtomicUsize::new(0);
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Name(String);
+impl Intern for Name {}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Files;
+impl Input for Files {
+    type Data = Vec<String>;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct File(String);
+impl Input for File {
+    type Data = String;
+
+    fn on_uninitialized(&self) -> Self::Data {
+        "<removed>".into()
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Count(String);
+
+#[async_trait]
+impl Query for Count {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let name = system.intern(Name(self.0.clone())).await;
+        let file = system.query_ref(File(self.0.clone())).await;
+        COUNTED.fetch_add(1, Ordering::SeqCst);
+        format!("{}:{}", system.lookup(name).await.0, file.len())
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Program;
+
+#[async_trait]
+impl Query for Program {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let mut counts = vec![];
+        for file in system.query(Files).await {
+            counts.push(system.query(Count(file)).await);
+        }
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        counts.into_iter().join(", ")
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn remove_input() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(Files, vec!["a".into(), "b".into()]).await;
+        system.set_input(File("a".into()), "1".into()).await;
+        system.set_input(File("b".into()), "22".into()).await;
+
+        assert_query!(system, "R3", "a:1, b:2", Program);
+        assert_eq!(COUNTED.load(Ordering::SeqCst), 2, "Counted count");
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Processed count");
+
+        tracing::info!("Remove input still in use");
+        system.remove_input(File("b".into())).await;
+        assert_query!(system, "R4", "<removed>", File("b".into()));
+        assert_query!(system, "R4", "a:1, b:9", Program);
+        assert_eq!(COUNTED.load(Ordering::SeqCst), 3, "Counted count");
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Processed count");
+
+        tracing::info!("Remove input nobody uses");
+        system.set_input(Files, vec!["a".into()]).await;
+        system.remove_input(File("b".into())).await;
+        assert_query!(system, "R5", "a:1", Program);
+        assert_eq!(COUNTED.load(Ordering::SeqCst), 3, "Counted count");
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 3, "Processed count");
+
+        tracing::info!("Removing twice does nothing");
+        let rev = system.current_rev();
+        system.remove_input(File("b".into())).await;
+        assert_eq!(rev, system.current_rev(), "Current revision");
+        assert_query!(system, "R5", "a:1", Program);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 3, "Processed count");
+    });
+}
@@ -0,0 +1,121 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use futures::FutureExt;
+use guacamole::{Cancelled, Input, Query, Runtime, System};
+use smol::Task;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct LongQuery(usize);
+
+#[async_trait]
+impl Query for LongQuery {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let o = system.query(A).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        format!("{} {}", o, self.0)
+    }
+}
+
+/// Forks the long query from inside of a query.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Nested(usize);
+
+#[async_trait]
+impl Query for Nested {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let n = self.0;
+        system
+            .fork(move |system| Task::spawn(async move { system.query(LongQuery(n)).await }))
+            .await
+            .await
+            .unwrap_or_default()
+    }
+}
+
+/// Bails out as soon as it's cancelled.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Checked;
+
+#[async_trait]
+impl Query for Checked {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.check_cancelled().await;
+        system.query(A).await
+    }
+}
+
+#[test]
+fn scope() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "a".into()).await;
+
+        let scope = system.scope();
+        let canceled = scope
+            .fork(|system| Task::spawn(async move { system.query(Nested(1)).await }))
+            .await;
+        let other = system
+            .fork(|system| Task::spawn(async move { system.query(Nested(2)).await }))
+            .await;
+
+        tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
+
+        tracing::info!("Cancel the scope");
+        scope.cancel();
+        assert!(scope.is_cancelled());
+        assert!(canceled.await.is_err());
+        assert_eq!(other.await, Ok("a 2".to_string()));
+
+        tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Processed count");
+
+        tracing::info!("Drop the scope");
+        let dropped = {
+            let scope = system.scope();
+            scope
+                .fork(|system| Task::spawn(async move { system.query(Nested(3)).await }))
+                .await
+        };
+        assert!(dropped.await.is_err());
+        assert!(!system.is_cancelled());
+        assert_eq!(system.query(Nested(4)).await, "a 4");
+    });
+}
+
+#[test]
+fn cancelled_scope() {
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "a".into()).await;
+
+        let scope = system.scope();
+        scope.cancel();
+        assert_eq!(scope.query_cancellable(Checked).await, Err(Cancelled));
+
+        tracing::info!("Querying a cancelled scope panics instead of hanging");
+        let panicked = AssertUnwindSafe(scope.query(Checked)).catch_unwind().await;
+        let message = panicked.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("Query cancelled"), "{}", message);
+
+        assert_eq!(system.query(Checked).await, "a");
+    });
+}
@@ -0,0 +1,184 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Input, Intern, Query, Runtime, SnapshotPolicy, System};
+use smol::Task;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+static WRITTEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+pub struct Double;
+
+#[async_trait]
+impl Query for Double {
+    type Output = String;
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let input = system.query(A).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        format!("{}{}", input, input)
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Name(&'static str);
+impl Intern for Name {}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct LongQuery;
+
+#[async_trait]
+impl Query for LongQuery {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let o = system.query(A).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+        o
+    }
+}
+
+macro_rules! assert_query {
+    ($system: expr, $rev: expr, $expected: expr, $query: expr) => {
+        let (out, rev) = $system.query_rev($query).await.unwrap();
+        assert_eq!(
+            format!("{:?}", rev),
+            $rev,
+            "Revision {}",
+            stringify!($query)
+        );
+        assert_eq!(out, $expected, "Query output {}", stringify!($query));
+    };
+}
+
+#[test]
+fn snapshot() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "2".into()).await;
+        assert_query!(system, "R1", "22", Double);
+
+        // Snapshot keeps seeing inputs of its revision
+        system.set_snapshot_policy(SnapshotPolicy::Ignore);
+        let snapshot = system.snapshot();
+        system.set_input(A, "3".into()).await;
+        assert_eq!(format!("{:?}", snapshot.revision()), "R1");
+        assert_query!(snapshot, "R1", "2", A);
+        assert_query!(snapshot, "R1", "22", Double);
+        assert_query!(system, "R2", "33", Double);
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Proccess count");
+
+        // Queries first calculated in a snapshot don't see newer inputs either
+        system.set_input(A, "4".into()).await;
+        assert_query!(snapshot, "R1", "2", LongQuery);
+        assert_query!(system, "R3", "4", LongQuery);
+        drop(snapshot);
+
+        // Forks of a snapshot are aborted by writes
+        system.set_snapshot_policy(SnapshotPolicy::Cancel);
+        let snapshot = system.snapshot();
+        let handle = snapshot
+            .fork(|system| Task::spawn(async move { system.query(LongQuery).await }))
+            .await;
+        system.set_input(A, "5".into()).await;
+        assert!(handle.await.is_err(), "Snapshot fork aborted");
+        assert_query!(snapshot, "R3", "44", Double);
+        drop(snapshot);
+
+        // Writes wait for snapshots to be dropped
+        system.set_snapshot_policy(SnapshotPolicy::Wait);
+        let snapshot = system.snapshot();
+        let write = async {
+            system.set_input(A, "6".into()).await;
+            WRITTEN.store(true, Ordering::SeqCst);
+        };
+        let read = async move {
+            assert_query!(snapshot, "R4", "55", Double);
+            tokio::time::delay_for(tokio::time::Duration::from_millis(50)).await;
+            assert!(!WRITTEN.load(Ordering::SeqCst), "Write waits for snapshot");
+        };
+        futures::future::join(write, read).await;
+        assert!(WRITTEN.load(Ordering::SeqCst), "Write done");
+        assert_query!(system, "R5", "66", Double);
+    });
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct B;
+impl Input for B {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Concat;
+#[async_trait]
+impl Query for Concat {
+    type Output = String;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        system.query(A).await + &system.query(B).await
+    }
+}
+
+#[test]
+fn copy_on_write() {
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "1".into()).await;
+        system.set_input(B, "2".into()).await;
+        assert_query!(system, "R2", "12", Concat);
+        assert_eq!(system.shared_storages(), 0);
+
+        system.set_snapshot_policy(SnapshotPolicy::Ignore);
+        let snapshot = system.snapshot();
+        assert_eq!(system.shared_storages(), 3);
+
+        tracing::info!("Only the written storage is copied");
+        system.set_input(B, "3".into()).await;
+        assert_eq!(system.shared_storages(), 2);
+        assert_query!(system, "R3", "3", B);
+        assert_query!(snapshot, "R2", "2", B);
+        assert_query!(snapshot, "R2", "12", Concat);
+        assert_eq!(system.shared_storages(), 2);
+
+        tracing::info!("Recalculating copies the storages it writes");
+        assert_query!(system, "R3", "13", Concat);
+        assert_eq!(system.shared_storages(), 0);
+        assert_query!(snapshot, "R2", "12", Concat);
+        assert_query!(snapshot, "R1", "1", A);
+
+        drop(snapshot);
+    });
+}
+
+#[test]
+fn intern_after_write() {
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "1".into()).await;
+        let a = system.intern(Name("a")).await;
+
+        system.set_snapshot_policy(SnapshotPolicy::Ignore);
+        let snapshot = system.snapshot();
+        system.set_input(A, "2".into()).await;
+
+        tracing::info!("Both sides intern into the same storage");
+        let b = snapshot.intern(Name("b")).await;
+        let c = system.intern(Name("c")).await;
+        assert_ne!(b, c);
+        assert_eq!(system.intern(Name("b")).await, b);
+        assert_eq!(snapshot.intern(Name("a")).await, a);
+        assert_eq!(system.lookup(b).await, Name("b"));
+        assert_eq!(snapshot.lookup(c).await, Name("c"));
+
+        drop(snapshot);
+    });
+}
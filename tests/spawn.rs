@@ -0,0 +1,109 @@
+#![cfg(feature = "smol")]
+
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Cancelled, Input, Query, Runtime, SmolSpawner, System};
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = usize;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct Square(usize);
+#[async_trait]
+impl Query for Square {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        self.0 * system.query(A).await
+    }
+}
+
+/// Sums squares calculated in spawned forks, whatever the executor.
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Sum;
+#[async_trait]
+impl Query for Sum {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let mut handles = vec![];
+        for n in 1..=3 {
+            handles.push(
+                system
+                    .spawn(move |system| async move { system.query(Square(n)).await })
+                    .await
+                    .expect("Spawner"),
+            );
+        }
+
+        let mut sum = 0;
+        for handle in handles {
+            sum += match handle.await {
+                Ok(square) => square,
+                Err(cancelled) => system.propagate(cancelled).await,
+            };
+        }
+        sum
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct LongQuery;
+#[async_trait]
+impl Query for LongQuery {
+    type Output = usize;
+
+    async fn calc<S: System>(&self, system: &S) -> Self::Output {
+        let o = system.query(A).await;
+        tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+        o
+    }
+}
+
+#[test]
+fn spawn_smol() {
+    init_log();
+
+    let system = Runtime::default();
+    system.set_spawner(SmolSpawner);
+    smol::run(async move {
+        system.set_input(A, 2).await;
+        assert_eq!(system.query(Sum).await, 12);
+
+        let handle = system
+            .spawn(|system| async move { system.query(LongQuery).await })
+            .await
+            .expect("Spawner");
+        tokio::time::delay_for(tokio::time::Duration::from_millis(30)).await;
+
+        tracing::info!("Spawned fork is canceled");
+        system.set_input(A, 3).await;
+        assert_eq!(handle.await, Err(Cancelled));
+
+        assert_eq!(system.query(Sum).await, 18);
+    });
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn spawn_tokio() {
+    let system = Runtime::default();
+    let mut executor = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_time()
+        .build()
+        .expect("Tokio runtime");
+    executor.block_on(async move {
+        system.set_input(A, 2).await;
+        assert_eq!(system.query(Sum).await, 12);
+
+        let handle = system
+            .spawn(|system| async move { system.query(Square(4)).await })
+            .await
+            .expect("Spawner");
+        assert_eq!(handle.await, Ok(8));
+    });
+}
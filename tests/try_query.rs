@@ -0,0 +1,149 @@
+use async_trait::async_trait;
+use guacamole::test_common::init_log;
+use guacamole::{Cycle, Input, QueryError, Runtime, System, TryQuery};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct A;
+impl Input for A {
+    type Data = String;
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Parse;
+
+#[async_trait]
+impl TryQuery for Parse {
+    type Ok = i32;
+    type Error = String;
+
+    async fn try_calc<S: System>(&self, system: &S) -> Result<i32, QueryError<String>> {
+        let input = system.query(A).await;
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        Ok(input
+            .parse()
+            .map_err(|_| format!("Invalid number {}", input))?)
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Double;
+
+#[async_trait]
+impl TryQuery for Double {
+    type Ok = i32;
+    type Error = String;
+
+    async fn try_calc<S: System>(&self, system: &S) -> Result<i32, QueryError<String>> {
+        Ok(system.try_query(Parse).await? * 2)
+    }
+}
+
+/// Fails with a transient error until it's attempted `self.0` times.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Flaky(usize);
+
+#[async_trait]
+impl TryQuery for Flaky {
+    type Ok = usize;
+    type Error = String;
+
+    const RETRIES: usize = 2;
+
+    async fn try_calc<S: System>(&self, _system: &S) -> Result<usize, QueryError<String>> {
+        let attempt = ATTEMPTS.fetch_add(1, Ordering::SeqCst) + 1;
+        if attempt < self.0 {
+            Err(QueryError::transient("Timeout".into()))
+        } else {
+            Ok(attempt)
+        }
+    }
+}
+
+/// Each of the two queries needs the other one.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Ping(bool);
+
+#[async_trait]
+impl TryQuery for Ping {
+    type Ok = usize;
+    type Error = String;
+
+    async fn try_calc<S: System>(&self, system: &S) -> Result<usize, QueryError<String>> {
+        Ok(system.try_query(Ping(!self.0)).await? + 1)
+    }
+
+    fn on_cycle(&self, cycle: &Cycle) -> Result<usize, QueryError<String>> {
+        Err(QueryError::new(format!("{} queries", cycle.queries().len())))
+    }
+}
+
+/// Counts up to `3` on a cycle, starting from `0`.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct Converge(bool);
+
+#[async_trait]
+impl TryQuery for Converge {
+    type Ok = usize;
+    type Error = String;
+
+    const CYCLE_ITERATIONS: usize = 10;
+
+    async fn try_calc<S: System>(&self, system: &S) -> Result<usize, QueryError<String>> {
+        let other = system.try_query(Converge(!self.0)).await?;
+        Ok((other + 1).min(3))
+    }
+
+    fn cycle_initial(&self) -> Option<Result<usize, QueryError<String>>> {
+        Some(Ok(0))
+    }
+}
+
+#[test]
+fn try_query() {
+    init_log();
+
+    let system = Runtime::default();
+    smol::run(async move {
+        system.set_input(A, "x".into()).await;
+
+        let err = system.try_query(Double).await.unwrap_err();
+        assert_eq!(err.error(), "Invalid number x");
+        assert_eq!(err.chain(), ["Parse", "Double"]);
+        assert_eq!(err.to_string(), "Invalid number x (in Parse <- Double)");
+
+        // Errors are memoized
+        assert_eq!(system.try_query(Double).await, Err(err));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 1, "Proccess count");
+
+        // and recalculated when dependencies change
+        system.set_input(A, "2".into()).await;
+        assert_eq!(system.try_query(Double).await, Ok(4));
+        assert_eq!(PROCESSED.load(Ordering::SeqCst), 2, "Proccess count");
+
+        // Transient errors are retried
+        assert_eq!(system.try_query(Flaky(3)).await, Ok(3));
+        ATTEMPTS.store(0, Ordering::SeqCst);
+        let err = system.try_query(Flaky(4)).await.unwrap_err();
+        assert!(err.is_transient());
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 3, "Attempts");
+        assert_eq!(system.try_query(Flaky(4)).await, Err(err));
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 3, "Attempts");
+    });
+}
+
+#[test]
+fn try_query_cycle() {
+    let system = Runtime::default();
+    smol::run(async move {
+        let err = system.try_query(Ping(true)).await.unwrap_err();
+        assert_eq!(err.error(), "2 queries");
+        assert_eq!(err.chain(), ["Ping(true)", "Ping(false)", "Ping(true)"]);
+
+        assert_eq!(system.try_query(Converge(true)).await, Ok(3));
+        assert_eq!(system.try_query(Converge(false)).await, Ok(3));
+    });
+}